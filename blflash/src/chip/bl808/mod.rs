@@ -0,0 +1,11 @@
+//! BL808, described by `chip.toml`
+
+pub const DESCRIPTION: &str = include_str!("chip.toml");
+pub const DEFAULT_PARTITION_CFG: &[u8] = include_bytes!("cfg/partition_cfg_16M.toml");
+pub const DEFAULT_BOOTHEADER_CFG: &[u8] = include_bytes!("cfg/efuse_bootheader_cfg.conf");
+
+/// Files named in `DESCRIPTION`
+pub const ASSETS: &[(&str, &[u8])] = &[
+    ("cfg/partition_cfg_16M.toml", DEFAULT_PARTITION_CFG),
+    ("cfg/efuse_bootheader_cfg.conf", DEFAULT_BOOTHEADER_CFG),
+];
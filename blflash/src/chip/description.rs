@@ -0,0 +1,433 @@
+use super::{firmware_segment, partition_segment, Chip, CodeSegment, FlashImages, RomSegment};
+use crate::{
+    image::{
+        Bl616ClkCfg, Bl808ClkCfg, BootHeader, BootHeaderCfg, BootHeaderCfgFile, GroupBootHeaderCfg,
+        PartitionCfg, BITFIELDS,
+    },
+    Error,
+};
+use serde::Deserialize;
+use std::{borrow::Cow, collections::BTreeMap, fs::read, io, path::Path};
+
+/// A chip described by a TOML file, see the built-in chips' `chip.toml`
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChipDescription {
+    /// Name shown to the user, also accepted by `--chip`
+    pub name: String,
+    /// Other names accepted by `--chip`
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Rust target the firmware is built for
+    pub target: String,
+    /// UF2 family ID of the chip's firmware
+    pub uf2_family_id: Option<u32>,
+    pub memory: MemoryMap,
+    pub assets: Assets,
+    pub segments: Segments,
+    pub boot_header: BootHeaderDescription,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MemoryMap {
+    /// Address flash is mapped at for XIP, ELF segments in the window are
+    /// written to flash at their offset from it
+    pub flash_start: u32,
+    /// Size of the XIP window
+    pub flash_window: u32,
+    /// Flash size the default partition table is made for
+    pub default_flash_size: u32,
+}
+
+/// Files the chip comes with, relative to the description
+#[derive(Debug, Deserialize, Clone)]
+pub struct Assets {
+    /// Partition table templates by flash size, e.g. `2M = "partition_cfg_2M.toml"`
+    pub partition_cfg: BTreeMap<String, String>,
+    pub boot_header_cfg: String,
+    pub boot2: Option<String>,
+    /// ro_params flashed when none is given
+    pub ro_params: Option<String>,
+    /// eflash_loader build the ROM runs to flash
+    pub eflash_loader: Option<String>,
+}
+
+/// Where images go in flash. Everything but boot2 is placed by the partition
+/// table: the firmware in `FW`, the table copies at the `pt_table` addresses.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Segments {
+    /// Flash address of the boot2 image
+    #[serde(default)]
+    pub boot2: u32,
+    /// Partition ro_params is written to, in slot 0
+    #[serde(default = "default_ro_params")]
+    pub ro_params: String,
+    /// Partition the image of each extra core goes to, e.g. `d0 = "D0FW"`
+    #[serde(default)]
+    pub core_partitions: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BootHeaderFormat {
+    /// The BL602/BL702 header
+    Bl602,
+    /// The grouped header with the BL808 clock config
+    Bl808,
+    /// The grouped header with the BL616 clock config
+    Bl616,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BootHeaderDescription {
+    pub format: BootHeaderFormat,
+    /// Length of the boot header the ROM reads in front of the eflash_loader
+    pub len: usize,
+    /// Cores with a `[BOOTHEADER_CFG.<core>]` table, for the grouped formats
+    #[serde(default)]
+    pub cores: Vec<String>,
+    /// Patch and reserved bytes before the CRC, for the grouped formats
+    #[serde(default)]
+    pub reserved_len: usize,
+    /// Offset of boot2 from its header, replacing the config's
+    pub boot2_offset: Option<u32>,
+    /// Offset of the firmware from its header, replacing the config's
+    pub fw_offset: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Protocol {
+    /// Sent to the ROM right after the 0x55 handshake
+    #[serde(default)]
+    pub handshake_suffix: Vec<u8>,
+    /// How to recognize the chip's `BootInfo`, never detected if not set
+    pub boot_info: Option<BootInfoMatch>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BootInfoMatch {
+    /// Length of the eFuse info
+    pub otp_len: usize,
+    /// ROM version after masking with `bootrom_version_mask`, any if not set
+    pub bootrom_version: Option<u32>,
+    #[serde(default = "full_mask")]
+    pub bootrom_version_mask: u32,
+}
+
+/// Parse a flash size like `512K` or `2M`
+fn parse_size(size: &str) -> Result<u32, Error> {
+    let invalid = || Error::InvalidFlashSize(size.to_string());
+    let upper = size.to_ascii_uppercase();
+    let (number, unit) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1 << 10),
+        Some('M') => (&upper[..upper.len() - 1], 1 << 20),
+        _ => return Err(invalid()),
+    };
+    number
+        .parse::<u32>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(invalid)
+}
+
+fn default_ro_params() -> String {
+    "factory".to_string()
+}
+
+fn full_mask() -> u32 {
+    0xffffffff
+}
+
+/// A `Chip` implemented from a `ChipDescription` and its assets
+#[derive(Clone)]
+pub struct DescribedChip {
+    description: ChipDescription,
+    /// Partition table templates by flash size, smallest first
+    partition_templates: Vec<(u32, String)>,
+    assets: BTreeMap<String, Cow<'static, [u8]>>,
+}
+
+impl DescribedChip {
+    /// A chip built into blflash, `assets` holds the files named by the
+    /// description
+    pub fn builtin(description: &str, assets: &[(&str, &'static [u8])]) -> Result<Self, Error> {
+        let assets: BTreeMap<_, _> = assets
+            .iter()
+            .map(|(name, data)| (name.to_string(), Cow::Borrowed(*data)))
+            .collect();
+        Self::new(toml::from_str(description)?, |name| {
+            assets
+                .get(name)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        })
+    }
+
+    /// Load a chip description, reading its assets relative to the file
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::new(toml::from_slice(&read(path)?)?, |name| {
+            read(dir.join(name)).map(Cow::Owned)
+        })
+    }
+
+    fn new(
+        description: ChipDescription,
+        mut load: impl FnMut(&str) -> io::Result<Cow<'static, [u8]>>,
+    ) -> Result<Self, Error> {
+        let mut partition_templates = description
+            .assets
+            .partition_cfg
+            .iter()
+            .map(|(size, name)| Ok((parse_size(size)?, name.clone())))
+            .collect::<Result<Vec<_>, Error>>()?;
+        partition_templates.sort_by_key(|(size, _)| *size);
+        let default_flash_size = description.memory.default_flash_size;
+        if !partition_templates
+            .iter()
+            .any(|(size, _)| *size == default_flash_size)
+        {
+            return Err(Error::NoPartitionTemplate(default_flash_size));
+        }
+
+        let assets = &description.assets;
+        let names = assets
+            .partition_cfg
+            .values()
+            .chain(std::iter::once(&assets.boot_header_cfg))
+            .chain(&assets.boot2)
+            .chain(&assets.ro_params)
+            .chain(&assets.eflash_loader);
+        let mut loaded = BTreeMap::new();
+        for name in names {
+            let data = load(name).map_err(|e| Error::ChipAsset(name.clone(), e))?;
+            loaded.insert(name.clone(), data);
+        }
+
+        Ok(DescribedChip {
+            description,
+            partition_templates,
+            assets: loaded,
+        })
+    }
+
+    pub fn description(&self) -> &ChipDescription {
+        &self.description
+    }
+
+    /// Whether `--chip` may name this chip with `name`
+    pub fn is_named(&self, name: &str) -> bool {
+        std::iter::once(&self.description.name)
+            .chain(&self.description.aliases)
+            .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+
+    fn asset(&self, name: &str) -> &[u8] {
+        &self.assets[name]
+    }
+
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        let memory = &self.description.memory;
+        addr >= memory.flash_start && addr - memory.flash_start < memory.flash_window
+    }
+}
+
+impl Chip for DescribedChip {
+    fn name(&self) -> &str {
+        &self.description.name
+    }
+
+    fn target(&self) -> &str {
+        &self.description.target
+    }
+
+    fn get_eflash_loader(&self) -> Option<&[u8]> {
+        self.description
+            .assets
+            .eflash_loader
+            .as_ref()
+            .map(|name| self.asset(name))
+    }
+
+    fn get_boot2(&self) -> Option<&[u8]> {
+        self.description
+            .assets
+            .boot2
+            .as_ref()
+            .map(|name| self.asset(name))
+    }
+
+    fn get_ro_params(&self) -> Option<&[u8]> {
+        self.description
+            .assets
+            .ro_params
+            .as_ref()
+            .map(|name| self.asset(name))
+    }
+
+    fn ro_params_partition(&self) -> &str {
+        &self.description.segments.ro_params
+    }
+
+    fn matches_boot_info(&self, bootrom_version: u32, otp_info: &[u8]) -> bool {
+        match &self.description.protocol.boot_info {
+            Some(boot_info) => {
+                otp_info.len() == boot_info.otp_len
+                    && boot_info.bootrom_version.is_none_or(|version| {
+                        bootrom_version & boot_info.bootrom_version_mask == version
+                    })
+            }
+            None => false,
+        }
+    }
+
+    fn partition_cfg_template(&self, flash_size: u32) -> Option<(u32, &[u8])> {
+        self.partition_templates
+            .iter()
+            .rev()
+            .find(|(size, _)| *size <= flash_size)
+            .map(|(size, name)| (*size, self.asset(name)))
+    }
+
+    fn uf2_family_id(&self) -> Option<u32> {
+        self.description.uf2_family_id
+    }
+
+    fn default_flash_size(&self) -> u32 {
+        self.description.memory.default_flash_size
+    }
+
+    fn default_boot_header_cfg(&self) -> &[u8] {
+        self.asset(&self.description.assets.boot_header_cfg)
+    }
+
+    fn read_boot_header(
+        &self,
+        data: &[u8],
+        overrides: &[(String, i64)],
+    ) -> Result<Box<dyn BootHeader>, Error> {
+        let header = &self.description.boot_header;
+        let offsets = [
+            ("boot2_offset", header.boot2_offset),
+            ("fw_offset", header.fw_offset),
+        ];
+        let overrides = offsets
+            .iter()
+            .filter_map(|(field, offset)| offset.map(|offset| (field.to_string(), offset as i64)))
+            .chain(overrides.iter().cloned())
+            .collect::<Vec<_>>();
+
+        Ok(match header.format {
+            BootHeaderFormat::Bl602 => Box::new(
+                BootHeaderCfgFile::<BootHeaderCfg>::from_toml_with_overrides(
+                    data, &overrides, BITFIELDS,
+                )?
+                .boot_header_cfg,
+            ),
+            BootHeaderFormat::Bl808 => {
+                Box::new(GroupBootHeaderCfg::<Bl808ClkCfg>::from_toml_with_overrides(
+                    data,
+                    &overrides,
+                    &header.cores,
+                    header.reserved_len,
+                )?)
+            }
+            BootHeaderFormat::Bl616 => {
+                Box::new(GroupBootHeaderCfg::<Bl616ClkCfg>::from_toml_with_overrides(
+                    data,
+                    &overrides,
+                    &header.cores,
+                    header.reserved_len,
+                )?)
+            }
+        })
+    }
+
+    fn boot_header_len(&self) -> usize {
+        self.description.boot_header.len
+    }
+
+    fn handshake_suffix(&self) -> &[u8] {
+        &self.description.protocol.handshake_suffix
+    }
+
+    fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>> {
+        if self.addr_is_flash(code_segment.addr) {
+            Some(RomSegment::from_code_segment(
+                code_segment.addr - self.description.memory.flash_start,
+                code_segment,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn with_boot2(
+        &self,
+        mut partition_cfg: PartitionCfg,
+        bootheader_cfg: &mut dyn BootHeader,
+        images: FlashImages,
+        fw_slot: Option<usize>,
+        compress: bool,
+    ) -> Result<Vec<RomSegment>, Error> {
+        let segments = &self.description.segments;
+        // An explicit slot keeps the device's tables, their ages and active slots
+        let write_tables = fw_slot.is_none();
+        let fw_slot = fw_slot.unwrap_or(0);
+        let boot2image =
+            bootheader_cfg.wrap_image(bootheader_cfg.boot2_offset() as usize, images.boot2)?;
+        let fw_segment = firmware_segment(
+            &mut partition_cfg,
+            bootheader_cfg,
+            &images.firmware,
+            fw_slot,
+            compress,
+        )?;
+
+        let mut core_segments = Vec::new();
+        for (core, image) in images.core_images {
+            let partition = segments
+                .core_partitions
+                .get(&core)
+                .ok_or_else(|| Error::UnknownCore(core.clone()))?;
+            bootheader_cfg.select_core(&core)?;
+            let image = bootheader_cfg.wrap_image(bootheader_cfg.fw_offset() as usize, image)?;
+            core_segments.push(partition_segment(
+                &partition_cfg,
+                partition,
+                fw_slot,
+                image,
+            )?);
+        }
+
+        let ro_params = match images.ro_params {
+            Some(ro_params) => Some(partition_segment(
+                &partition_cfg,
+                &segments.ro_params,
+                0,
+                ro_params,
+            )?),
+            None => None,
+        };
+
+        let table = [
+            partition_cfg.pt_table.address0,
+            partition_cfg.pt_table.address1,
+        ];
+        let partition_cfg = partition_cfg.to_flash()?;
+
+        let mut segments = vec![RomSegment::from_vec(segments.boot2, boot2image)];
+        if write_tables {
+            segments.push(RomSegment::from_vec(table[0], partition_cfg.clone()));
+            segments.push(RomSegment::from_vec(table[1], partition_cfg));
+        } else {
+            log::info!("Leaving the partition tables on the device as they are");
+        }
+        segments.push(fw_segment);
+        segments.extend(core_segments);
+        segments.extend(ro_params);
+
+        Ok(segments)
+    }
+}
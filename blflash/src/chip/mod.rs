@@ -1,18 +1,300 @@
 pub mod bl602;
+pub mod bl616;
+pub mod bl702;
+pub mod bl808;
+mod description;
 pub use crate::elf::{CodeSegment, FirmwareImage, RomSegment};
-use crate::image::{BootHeaderCfg, PartitionCfg};
+use crate::image::{compress_xz, BootHeader, PartitionCfg, FW_TYPE};
 use crate::Error;
-pub use bl602::Bl602;
+pub use description::{
+    Assets, BootHeaderDescription, BootHeaderFormat, BootInfoMatch, ChipDescription, DescribedChip,
+    MemoryMap, Protocol, Segments,
+};
+use once_cell::sync::Lazy;
+use std::{fmt, path::Path, str::FromStr, sync::Arc};
+
+/// Chip family selected with `--chip`
+#[derive(Clone)]
+pub enum ChipType {
+    Bl602,
+    Bl702,
+    Bl808,
+    Bl616,
+    /// A chip description file passed to `--chip`
+    Custom(Arc<DescribedChip>),
+}
+
+impl ChipType {
+    /// The built-in chips, in the order they are tried when detecting
+    pub const ALL: &'static [ChipType] = &[
+        ChipType::Bl602,
+        ChipType::Bl702,
+        ChipType::Bl808,
+        ChipType::Bl616,
+    ];
+
+    /// Find the built-in chip whose boot ROM sent this `BootInfo` response
+    pub fn detect(bootrom_version: u32, otp_info: &[u8]) -> Result<ChipType, Error> {
+        let matches = Self::ALL
+            .iter()
+            .filter(|chip_type| {
+                chip_type
+                    .chip()
+                    .matches_boot_info(bootrom_version, otp_info)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        match matches.as_slice() {
+            [] => Err(Error::UnrecognizedChip),
+            [chip_type] => Ok(chip_type.clone()),
+            chip_types => Err(Error::AmbiguousChip(
+                chip_types
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        }
+    }
+
+    pub fn chip(&self) -> Box<dyn Chip> {
+        Box::new(self.described().clone())
+    }
+
+    fn described(&self) -> &DescribedChip {
+        static BL602: Lazy<DescribedChip> =
+            Lazy::new(|| builtin(bl602::DESCRIPTION, bl602::ASSETS));
+        static BL702: Lazy<DescribedChip> =
+            Lazy::new(|| builtin(bl702::DESCRIPTION, bl702::ASSETS));
+        static BL808: Lazy<DescribedChip> =
+            Lazy::new(|| builtin(bl808::DESCRIPTION, bl808::ASSETS));
+        static BL616: Lazy<DescribedChip> =
+            Lazy::new(|| builtin(bl616::DESCRIPTION, bl616::ASSETS));
+        match self {
+            ChipType::Bl602 => &BL602,
+            ChipType::Bl702 => &BL702,
+            ChipType::Bl808 => &BL808,
+            ChipType::Bl616 => &BL616,
+            ChipType::Custom(chip) => chip,
+        }
+    }
+}
+
+/// Parsed once per run, the `built_in_descriptions_are_valid` test keeps the
+/// descriptions from failing here
+fn builtin(description: &str, assets: &[(&str, &'static [u8])]) -> DescribedChip {
+    DescribedChip::builtin(description, assets).expect("built-in chip description is valid")
+}
+
+impl FromStr for ChipType {
+    type Err = Error;
+
+    /// A built-in chip's name, or the path of a chip description file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(chip_type) = Self::ALL
+            .iter()
+            .find(|chip_type| chip_type.described().is_named(s))
+        {
+            return Ok(chip_type.clone());
+        }
+        let path = Path::new(s);
+        if path.is_file() {
+            return Ok(ChipType::Custom(Arc::new(DescribedChip::from_file(path)?)));
+        }
+        Err(Error::UnknownChip(s.to_string()))
+    }
+}
+
+impl fmt::Display for ChipType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.described().name().to_ascii_lowercase())
+    }
+}
 
 pub trait Chip {
-    fn target(&self) -> &'static str;
-    fn get_eflash_loader(&self) -> &[u8];
+    fn name(&self) -> &str;
+    fn target(&self) -> &str;
+    /// The bundled eflash_loader build, if there is one
+    fn get_eflash_loader(&self) -> Option<&[u8]>;
+    /// The bundled boot2 build, if there is one
+    fn get_boot2(&self) -> Option<&[u8]>;
+    /// ro_params flashed when none is given, None if the chip doesn't need one
+    fn get_ro_params(&self) -> Option<&[u8]>;
+    /// Partition holding ro_params
+    fn ro_params_partition(&self) -> &str;
+    /// Whether a `BootInfo` response with this ROM version and eFuse info
+    /// comes from this chip
+    fn matches_boot_info(&self, bootrom_version: u32, otp_info: &[u8]) -> bool;
+    /// The largest bundled partition table that fits a flash of `flash_size`
+    /// bytes, with the flash size it is made for
+    fn partition_cfg_template(&self, flash_size: u32) -> Option<(u32, &[u8])>;
+    fn default_partition_cfg(&self) -> &[u8] {
+        self.partition_cfg_template(self.default_flash_size())
+            .expect("chip has a partition table for its default flash size")
+            .1
+    }
+    /// UF2 family ID of the chip's firmware, other families' blocks are skipped
+    fn uf2_family_id(&self) -> Option<u32>;
+    /// Flash size assumed when it isn't given or detected
+    fn default_flash_size(&self) -> u32;
+    fn default_boot_header_cfg(&self) -> &[u8];
+    /// Parse the chip's `efuse_bootheader_cfg.conf`, applying `field = value`
+    /// overrides in order
+    fn read_boot_header(
+        &self,
+        data: &[u8],
+        overrides: &[(String, i64)],
+    ) -> Result<Box<dyn BootHeader>, Error>;
+    /// Length of the boot header the ROM reads in front of the eflash_loader
+    fn boot_header_len(&self) -> usize;
+    /// Sent to the ROM right after the 0x55 handshake
+    fn handshake_suffix(&self) -> &[u8];
     fn get_flash_segment<'a>(&self, code_segment: CodeSegment<'a>) -> Option<RomSegment<'a>>;
     fn with_boot2(
         &self,
         partition_cfg: PartitionCfg,
-        bootheader_cfg: BootHeaderCfg,
-        ro_params: Vec<u8>,
-        bin: &[u8],
+        bootheader_cfg: &mut dyn BootHeader,
+        images: FlashImages,
+        fw_slot: Option<usize>,
+        compress: bool,
     ) -> Result<Vec<RomSegment>, Error>;
 }
+
+/// Images `Chip::with_boot2` lays out around the partition table
+pub struct FlashImages {
+    pub boot2: Vec<u8>,
+    /// Firmware of the boot core, for the FW partition
+    pub firmware: Vec<u8>,
+    pub ro_params: Option<Vec<u8>>,
+    /// Firmware of the other cores by core name, for chips with more than one
+    pub core_images: Vec<(String, Vec<u8>)>,
+}
+
+/// Make the firmware image and place it in slot `fw_slot` of the FW
+/// partition, setting the entry's `len` when compressing
+pub(crate) fn firmware_segment(
+    partition_cfg: &mut PartitionCfg,
+    bootheader_cfg: &mut dyn BootHeader,
+    bin: &[u8],
+    fw_slot: usize,
+    compress: bool,
+) -> Result<RomSegment<'static>, Error> {
+    let mut fw_image =
+        bootheader_cfg.wrap_image(bootheader_cfg.fw_offset() as usize, Vec::from(bin))?;
+
+    let fw = partition_cfg
+        .find_type_mut(FW_TYPE)
+        .ok_or_else(|| Error::PartitionNotFound("FW".to_string()))?;
+    let fw_range = fw
+        .slot(fw_slot)
+        .ok_or_else(|| Error::NoSuchSlot(fw.name.clone(), fw_slot))?;
+    if compress {
+        // boot2 decompresses the image into the other slot on first boot
+        let other_slot = 1 - fw_slot.min(1);
+        let other_range = fw
+            .slot(other_slot)
+            .ok_or_else(|| Error::NoSuchSlot(fw.name.clone(), other_slot))?;
+        if fw_image.len() > other_range.len() {
+            return Err(Error::PartitionTooSmall(
+                format!("{} slot {}", fw.name, other_slot),
+                fw_image.len(),
+                other_range.len(),
+            ));
+        }
+        let compressed = compress_xz(&fw_image)?;
+        log::info!(
+            "Compressed firmware {} -> {} bytes",
+            fw_image.len(),
+            compressed.len()
+        );
+        fw_image = compressed;
+        fw.len = fw_image.len() as u32;
+    }
+    if fw_image.len() > fw_range.len() {
+        return Err(Error::PartitionTooSmall(
+            format!("{} slot {}", fw.name, fw_slot),
+            fw_image.len(),
+            fw_range.len(),
+        ));
+    }
+
+    Ok(RomSegment::from_vec(fw_range.start, fw_image))
+}
+
+/// Place `data` in slot `slot` of the partition called `name`
+pub(crate) fn partition_segment(
+    partition_cfg: &PartitionCfg,
+    name: &str,
+    slot: usize,
+    data: Vec<u8>,
+) -> Result<RomSegment<'static>, Error> {
+    let entry = partition_cfg
+        .find_entry(name)
+        .ok_or_else(|| Error::PartitionNotFound(name.to_string()))?;
+    let range = entry
+        .slot(slot)
+        .ok_or_else(|| Error::NoSuchSlot(entry.name.clone(), slot))?;
+    if data.len() > range.len() {
+        return Err(Error::PartitionTooSmall(
+            format!("{} slot {}", entry.name, slot),
+            data.len(),
+            range.len(),
+        ));
+    }
+    Ok(RomSegment::from_vec(range.start, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_descriptions_are_valid() {
+        let sources = [
+            (bl602::DESCRIPTION, bl602::ASSETS),
+            (bl702::DESCRIPTION, bl702::ASSETS),
+            (bl808::DESCRIPTION, bl808::ASSETS),
+            (bl616::DESCRIPTION, bl616::ASSETS),
+        ];
+        assert_eq!(sources.len(), ChipType::ALL.len());
+        for (description, assets) in &sources {
+            DescribedChip::builtin(description, assets).unwrap();
+        }
+        for chip_type in ChipType::ALL {
+            let chip = chip_type.chip();
+            let parsed: ChipType = chip.name().parse().unwrap();
+            assert_eq!(parsed.to_string(), chip_type.to_string());
+            chip.read_boot_header(chip.default_boot_header_cfg(), &[])
+                .unwrap();
+            toml::from_slice::<PartitionCfg>(chip.default_partition_cfg()).unwrap();
+        }
+    }
+
+    fn detect(bootrom_version: u32, otp_len: usize) -> Result<String, Error> {
+        ChipType::detect(bootrom_version, &vec![0; otp_len]).map(|chip_type| chip_type.to_string())
+    }
+
+    #[test]
+    fn detects_bl602() {
+        assert_eq!(detect(1, 16).unwrap(), "bl602");
+    }
+
+    #[test]
+    fn detects_bl702() {
+        assert_eq!(detect(0x07020001, 16).unwrap(), "bl702");
+    }
+
+    #[test]
+    fn reports_bl808_and_bl616_as_ambiguous() {
+        assert!(matches!(
+            detect(0, 20),
+            Err(Error::AmbiguousChip(chips)) if chips == "bl808, bl616"
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_boot_info() {
+        assert!(matches!(detect(1, 12), Err(Error::UnrecognizedChip)));
+        assert!(matches!(detect(2, 16), Err(Error::UnrecognizedChip)));
+    }
+}
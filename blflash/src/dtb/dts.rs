@@ -0,0 +1,449 @@
+use super::{DeviceTree, Node};
+use crate::Error;
+use std::fmt::Write as _;
+
+/// Parse device tree source into a tree.
+///
+/// Covers what the vendor `bl_factory_params_*.dts` files use: nodes,
+/// labels, strings, cells, byte strings and `/bits/`. Preprocessor
+/// directives, references and `/delete-*/` are not supported.
+pub fn parse_dts(source: &str) -> Result<DeviceTree, Error> {
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim_start();
+        if ["#include", "#define", "#if", "/include/"]
+            .iter()
+            .any(|directive| line.starts_with(directive))
+        {
+            return Err(Error::DtsParse(
+                index + 1,
+                "includes and preprocessor directives are not supported".to_string(),
+            ));
+        }
+    }
+
+    let mut parser = Parser { source, pos: 0 };
+    let mut tree = DeviceTree::default();
+    loop {
+        parser.skip_whitespace();
+        if parser.pos == source.len() {
+            break;
+        }
+        if parser.eat("/dts-v1/") {
+            parser.expect(";")?;
+        } else if parser.eat("/memreserve/") {
+            let address = parser.number(64)?;
+            let size = parser.number(64)?;
+            parser.expect(";")?;
+            tree.reservations.push((address, size));
+        } else if parser.eat("/") {
+            parser.node_body(&mut tree.root)?;
+            parser.expect(";")?;
+        } else {
+            return parser.error("expected a root node");
+        }
+    }
+
+    Ok(tree)
+}
+
+/// Parse a single property value in DTS syntax, e.g. `<0x1c200>`, `"MF"`
+/// or `[c8 43 57 82 73 40]`
+pub fn parse_value(source: &str) -> Result<Vec<u8>, Error> {
+    let mut parser = Parser { source, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != source.len() {
+        return parser.error("unexpected data after the value");
+    }
+    Ok(value)
+}
+
+/// Render a value the way dtc decompiles it: strings when it looks like a
+/// string list, cells when it is a multiple of 4 bytes, bytes otherwise.
+pub fn format_value(value: &[u8]) -> String {
+    let is_strings = value.last() == Some(&0)
+        && value[..value.len() - 1]
+            .split(|b| *b == 0)
+            .all(|s| !s.is_empty() && s.iter().all(|b| (0x20..0x7f).contains(b)));
+    if is_strings {
+        value[..value.len() - 1]
+            .split(|b| *b == 0)
+            .map(|s| {
+                let s = String::from_utf8_lossy(s);
+                format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else if value.len().is_multiple_of(4) {
+        let cells = value
+            .chunks(4)
+            .map(|c| format!("{:#x}", u32::from_be_bytes([c[0], c[1], c[2], c[3]])))
+            .collect::<Vec<_>>();
+        format!("<{}>", cells.join(" "))
+    } else {
+        let bytes = value
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>();
+        format!("[{}]", bytes.join(" "))
+    }
+}
+
+fn write_node(out: &mut String, node: &Node, depth: usize) {
+    let indent = "\t".repeat(depth);
+    let name = if depth == 0 { "/" } else { &node.name };
+    writeln!(out, "{}{} {{", indent, name).unwrap();
+    for property in &node.properties {
+        if property.value.is_empty() {
+            writeln!(out, "{}\t{};", indent, property.name).unwrap();
+        } else {
+            writeln!(
+                out,
+                "{}\t{} = {};",
+                indent,
+                property.name,
+                format_value(&property.value)
+            )
+            .unwrap();
+        }
+    }
+    for child in &node.children {
+        writeln!(out).unwrap();
+        write_node(out, child, depth + 1);
+    }
+    writeln!(out, "{}}};", indent).unwrap();
+}
+
+impl DeviceTree {
+    /// Decompile to DTS source that `parse_dts` compiles back to the same tree
+    pub fn to_dts(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "/dts-v1/;").unwrap();
+        writeln!(out).unwrap();
+        for (address, size) in &self.reservations {
+            writeln!(out, "/memreserve/ {:#x} {:#x};", address, size).unwrap();
+        }
+        write_node(&mut out, &self.root, 0);
+        out
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        let line = self.source[..self.pos].matches('\n').count() + 1;
+        Err(Error::DtsParse(line, message.to_string()))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                self.pos += trimmed
+                    .find("*/")
+                    .map(|end| end + 2)
+                    .unwrap_or(trimmed.len());
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), Error> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            self.error(&format!("expected {:?}", token))
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn name(&mut self) -> Result<&'a str, Error> {
+        self.skip_whitespace();
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || ",._+*#?@-".contains(c));
+        if name.is_empty() {
+            return self.error("expected a node or property name");
+        }
+        Ok(name)
+    }
+
+    fn node_body(&mut self, node: &mut Node) -> Result<(), Error> {
+        self.expect("{")?;
+        while !self.eat("}") {
+            if self.peek() == Some('/') {
+                return self.error("directives inside nodes are not supported");
+            }
+            let mut name = self.name()?;
+            // labels are accepted but not used
+            while self.eat(":") {
+                name = self.name()?;
+            }
+            if self.peek() == Some('{') {
+                self.node_body(node.child_or_insert(name))?;
+            } else {
+                let value = if self.eat("=") {
+                    self.value()?
+                } else {
+                    Vec::new()
+                };
+                node.set_property(name, value);
+            }
+            self.expect(";")?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Vec<u8>, Error> {
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                Some('"') => self.string(&mut value)?,
+                Some('<') => self.cells(32, &mut value)?,
+                Some('[') => self.bytes(&mut value)?,
+                Some('/') if self.eat("/bits/") => {
+                    let bits = self.number(32)?;
+                    if ![8, 16, 32, 64].contains(&bits) {
+                        return self.error("/bits/ must be 8, 16, 32 or 64");
+                    }
+                    self.cells(bits as u32, &mut value)?;
+                }
+                Some('&') => return self.error("references are not supported"),
+                _ => return self.error("expected a property value"),
+            }
+            if !self.eat(",") {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    fn string(&mut self, value: &mut Vec<u8>) -> Result<(), Error> {
+        self.expect("\"")?;
+        let mut chars = self.rest().char_indices();
+        loop {
+            let (index, c) = match chars.next() {
+                Some(next) => next,
+                None => return self.error("unterminated string"),
+            };
+            match c {
+                '"' => {
+                    self.pos += index + 1;
+                    break;
+                }
+                '\\' => {
+                    let (_, escaped) = match chars.next() {
+                        Some(next) => next,
+                        None => return self.error("unterminated string"),
+                    };
+                    let byte = match escaped {
+                        'n' => b'\n',
+                        't' => b'\t',
+                        'r' => b'\r',
+                        'a' => 0x07,
+                        'b' => 0x08,
+                        'v' => 0x0b,
+                        'f' => 0x0c,
+                        '0' => 0,
+                        'x' => {
+                            let digits = chars
+                                .clone()
+                                .take(2)
+                                .take_while(|(_, c)| c.is_ascii_hexdigit());
+                            let digits: String = digits.map(|(_, c)| c).collect();
+                            for _ in 0..digits.len() {
+                                chars.next();
+                            }
+                            match u8::from_str_radix(&digits, 16) {
+                                Ok(byte) => byte,
+                                Err(_) => return self.error("invalid \\x escape"),
+                            }
+                        }
+                        c => {
+                            let mut buf = [0; 4];
+                            value.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                            continue;
+                        }
+                    };
+                    value.push(byte);
+                }
+                c => {
+                    let mut buf = [0; 4];
+                    value.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+        value.push(0);
+        Ok(())
+    }
+
+    fn cells(&mut self, bits: u32, value: &mut Vec<u8>) -> Result<(), Error> {
+        self.expect("<")?;
+        while !self.eat(">") {
+            let cell = match self.peek() {
+                Some('\'') => self.char_literal()?,
+                Some('(') => return self.error("expressions are not supported"),
+                Some('&') => return self.error("references are not supported"),
+                _ => self.number(bits)?,
+            };
+            let bytes = cell.to_be_bytes();
+            value.extend_from_slice(&bytes[bytes.len() - bits as usize / 8..]);
+        }
+        Ok(())
+    }
+
+    fn bytes(&mut self, value: &mut Vec<u8>) -> Result<(), Error> {
+        self.expect("[")?;
+        while !self.eat("]") {
+            let digits = self.rest().get(..2).unwrap_or("");
+            match u8::from_str_radix(digits, 16) {
+                Ok(byte) if digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    self.pos += 2;
+                    value.push(byte);
+                }
+                _ => return self.error("expected two hex digits in byte string"),
+            }
+        }
+        Ok(())
+    }
+
+    /// A character literal, a single byte like in dtc
+    fn char_literal(&mut self) -> Result<u64, Error> {
+        self.expect("'")?;
+        let c = match self.rest().chars().next() {
+            Some(c) if c != '\'' && c != '\\' => c,
+            _ => return self.error("unsupported character literal"),
+        };
+        if !c.is_ascii() {
+            return self.error(&format!("{:?} is more than one byte", c));
+        }
+        self.pos += c.len_utf8();
+        self.expect("'")?;
+        Ok(c as u64)
+    }
+
+    /// An integer literal that must fit in `bits`, negative values are stored
+    /// in two's complement.
+    fn number(&mut self, bits: u32) -> Result<u64, Error> {
+        let negative = self.eat("-");
+        self.skip_whitespace();
+        let literal = self.take_while(|c| c.is_ascii_alphanumeric());
+        let digits = literal.trim_end_matches(&['u', 'U', 'l', 'L'][..]);
+        let parsed = if let Some(hex) = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            u64::from_str_radix(hex, 16)
+        } else if digits.len() > 1 && digits.starts_with('0') {
+            u64::from_str_radix(&digits[1..], 8)
+        } else {
+            digits.parse()
+        };
+        let number = match parsed {
+            Ok(number) => number,
+            Err(_) => return self.error(&format!("invalid number {:?}", literal)),
+        };
+
+        let mask = mask(bits);
+        if number > mask {
+            return self.error(&format!("{} doesn't fit in {} bits", literal, bits));
+        }
+        Ok(if negative {
+            number.wrapping_neg() & mask
+        } else {
+            number
+        })
+    }
+}
+
+/// The largest value of a `bits` wide cell
+fn mask(bits: u32) -> u64 {
+    if bits == 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(source: &str) -> String {
+        match parse_dts(source) {
+            Err(Error::DtsParse(_, message)) => message,
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_cells_strings_and_bytes() {
+        let tree = parse_dts(
+            "/dts-v1/;\n/ {\n  label: node@1 {\n    cells = /bits/ 16 <0x1234 'A' -1>;\n    \
+             str = \"MF\", \"\\x41\";\n    data = [c8 43];\n  };\n};\n",
+        )
+        .unwrap();
+        let node = tree.node("/node@1").unwrap();
+        assert_eq!(
+            node.property("cells").unwrap().value,
+            [0x12, 0x34, 0x00, 0x41, 0xff, 0xff]
+        );
+        assert_eq!(node.property("str").unwrap().value, b"MF\0A\0");
+        assert_eq!(node.property("data").unwrap().value, [0xc8, 0x43]);
+    }
+
+    #[test]
+    fn rejects_unterminated_string() {
+        parse_error("/ { str = \"abc; };");
+    }
+
+    #[test]
+    fn rejects_unsupported_bits() {
+        parse_error("/ { cells = /bits/ 7 <1>; };");
+    }
+
+    #[test]
+    fn rejects_numbers_too_wide() {
+        assert!(parse_error("/ { cells = /bits/ 8 <0x100>; };").contains("8 bits"));
+        assert!(parse_error("/ { cells = <0x100000000>; };").contains("32 bits"));
+    }
+
+    #[test]
+    fn rejects_multibyte_char_literals() {
+        assert!(parse_error("/ { cells = /bits/ 8 <'é'>; };").contains("one byte"));
+        assert!(parse_error("/ { cells = <'€'>; };").contains("one byte"));
+        assert_eq!(parse_value("/bits/ 8 <'A'>").unwrap(), [0x41]);
+    }
+}
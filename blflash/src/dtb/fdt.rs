@@ -0,0 +1,259 @@
+use super::{DeviceTree, Node, Property};
+use crate::Error;
+use std::convert::TryInto;
+
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_LEN: usize = 40;
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn pad4(out: &mut Vec<u8>) {
+    out.resize((out.len() + 3) & !3, 0);
+}
+
+/// Offset of `name` in the strings block, reusing an existing string or the
+/// tail of a longer one the same way dtc does.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut needle = name.as_bytes().to_vec();
+    needle.push(0);
+    match strings.windows(needle.len()).position(|w| w == &needle[..]) {
+        Some(offset) => offset as u32,
+        None => {
+            let offset = strings.len() as u32;
+            strings.append(&mut needle);
+            offset
+        }
+    }
+}
+
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    push_u32(structure, FDT_BEGIN_NODE);
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    pad4(structure);
+
+    for property in &node.properties {
+        push_u32(structure, FDT_PROP);
+        push_u32(structure, property.value.len() as u32);
+        push_u32(structure, string_offset(strings, &property.name));
+        structure.extend_from_slice(&property.value);
+        pad4(structure);
+    }
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+
+    push_u32(structure, FDT_END_NODE);
+}
+
+/// Cursor over the structure block
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .ok_or(Error::InvalidDtb("structure block is truncated"))?;
+        self.pos += 4;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(Error::InvalidDtb("structure block is truncated"))?;
+        self.pos = (self.pos + len + 3) & !3;
+        Ok(bytes)
+    }
+    fn name(&mut self) -> Result<String, Error> {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(Error::InvalidDtb("unterminated node name"))?;
+        let name = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos = (self.pos + len + 1 + 3) & !3;
+        Ok(name)
+    }
+    /// The next token that isn't a NOP
+    fn token(&mut self) -> Result<u32, Error> {
+        loop {
+            match self.u32()? {
+                FDT_NOP => continue,
+                token => return Ok(token),
+            }
+        }
+    }
+}
+
+fn read_string(strings: &[u8], offset: u32) -> Result<String, Error> {
+    let rest = strings
+        .get(offset as usize..)
+        .ok_or(Error::InvalidDtb("property name out of the strings block"))?;
+    let len = rest
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(Error::InvalidDtb("unterminated property name"))?;
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+fn read_node(reader: &mut Reader, strings: &[u8]) -> Result<Node, Error> {
+    let mut node = Node::new(&reader.name()?);
+    loop {
+        match reader.token()? {
+            FDT_PROP => {
+                let len = reader.u32()? as usize;
+                let name = read_string(strings, reader.u32()?)?;
+                let value = reader.bytes(len)?.to_vec();
+                node.properties.push(Property { name, value });
+            }
+            FDT_BEGIN_NODE => node.children.push(read_node(reader, strings)?),
+            FDT_END_NODE => return Ok(node),
+            _ => return Err(Error::InvalidDtb("unexpected token")),
+        }
+    }
+}
+
+impl DeviceTree {
+    /// Parse a flattened device tree. Trailing data after `totalsize`, such as
+    /// the erased rest of a partition, is ignored.
+    pub fn from_fdt(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < FDT_HEADER_LEN {
+            return Err(Error::InvalidDtb("header is truncated"));
+        }
+        let header = |index: usize| {
+            u32::from_be_bytes(data[index * 4..index * 4 + 4].try_into().unwrap()) as usize
+        };
+        if header(0) as u32 != FDT_MAGIC {
+            return Err(Error::InvalidDtb("bad magic"));
+        }
+        if (header(6) as u32) > FDT_VERSION {
+            return Err(Error::InvalidDtb("unsupported version"));
+        }
+        let total_size = header(1);
+        let data = data
+            .get(..total_size)
+            .ok_or(Error::InvalidDtb("data is shorter than totalsize"))?;
+        let (off_dt_struct, off_dt_strings, off_mem_rsvmap) = (header(2), header(3), header(4));
+        let strings = data
+            .get(off_dt_strings..off_dt_strings + header(8))
+            .ok_or(Error::InvalidDtb("strings block out of bounds"))?;
+        let structure = data
+            .get(off_dt_struct..off_dt_struct + header(9))
+            .ok_or(Error::InvalidDtb("structure block out of bounds"))?;
+
+        let mut reservations = Vec::new();
+        let mut rsvmap = Reader {
+            data: data
+                .get(off_mem_rsvmap..)
+                .ok_or(Error::InvalidDtb("reservation map out of bounds"))?,
+            pos: 0,
+        };
+        loop {
+            let address = (rsvmap.u32()? as u64) << 32 | rsvmap.u32()? as u64;
+            let size = (rsvmap.u32()? as u64) << 32 | rsvmap.u32()? as u64;
+            if address == 0 && size == 0 {
+                break;
+            }
+            reservations.push((address, size));
+        }
+
+        let mut reader = Reader {
+            data: structure,
+            pos: 0,
+        };
+        if reader.token()? != FDT_BEGIN_NODE {
+            return Err(Error::InvalidDtb("missing root node"));
+        }
+        let root = read_node(&mut reader, strings)?;
+        if reader.token()? != FDT_END {
+            return Err(Error::InvalidDtb("missing end token"));
+        }
+
+        Ok(DeviceTree { reservations, root })
+    }
+
+    /// Serialize to the flattened device tree format
+    pub fn to_fdt(&self) -> Vec<u8> {
+        let mut rsvmap = Vec::new();
+        for (address, size) in &self.reservations {
+            rsvmap.extend_from_slice(&address.to_be_bytes());
+            rsvmap.extend_from_slice(&size.to_be_bytes());
+        }
+        rsvmap.extend_from_slice(&[0; 16]);
+
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        write_node(&self.root, &mut structure, &mut strings);
+        push_u32(&mut structure, FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_LEN;
+        let off_dt_struct = off_mem_rsvmap + rsvmap.len();
+        let off_dt_strings = off_dt_struct + structure.len();
+        let total_size = off_dt_strings + strings.len();
+
+        let mut fdt = Vec::with_capacity(total_size);
+        push_u32(&mut fdt, FDT_MAGIC);
+        push_u32(&mut fdt, total_size as u32);
+        push_u32(&mut fdt, off_dt_struct as u32);
+        push_u32(&mut fdt, off_dt_strings as u32);
+        push_u32(&mut fdt, off_mem_rsvmap as u32);
+        push_u32(&mut fdt, FDT_VERSION);
+        push_u32(&mut fdt, FDT_LAST_COMP_VERSION);
+        // boot_cpuid_phys
+        push_u32(&mut fdt, 0);
+        push_u32(&mut fdt, strings.len() as u32);
+        push_u32(&mut fdt, structure.len() as u32);
+        fdt.append(&mut rsvmap);
+        fdt.append(&mut structure);
+        fdt.append(&mut strings);
+
+        fdt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::bl602;
+    use crate::dtb::parse_dts;
+
+    #[test]
+    fn ro_params_round_trip_through_dts() {
+        let tree = DeviceTree::from_fdt(bl602::RO_PARAMS).unwrap();
+        let reparsed = parse_dts(&tree.to_dts()).unwrap();
+        assert_eq!(reparsed, tree);
+        assert_eq!(reparsed.to_fdt(), tree.to_fdt());
+        assert_eq!(DeviceTree::from_fdt(&tree.to_fdt()).unwrap(), tree);
+    }
+
+    #[test]
+    fn ignores_trailing_data() {
+        let mut data = bl602::RO_PARAMS.to_vec();
+        let tree = DeviceTree::from_fdt(&data).unwrap();
+        data.resize(data.len() + 0x100, 0xff);
+        assert_eq!(DeviceTree::from_fdt(&data).unwrap(), tree);
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_truncation() {
+        let mut data = bl602::RO_PARAMS.to_vec();
+        assert!(DeviceTree::from_fdt(&data[..FDT_HEADER_LEN - 1]).is_err());
+        assert!(DeviceTree::from_fdt(&data[..data.len() / 2]).is_err());
+        data[0] ^= 0xff;
+        assert!(DeviceTree::from_fdt(&data).is_err());
+    }
+}
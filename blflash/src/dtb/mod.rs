@@ -0,0 +1,129 @@
+mod dts;
+mod fdt;
+
+pub use dts::{format_value, parse_dts, parse_value};
+pub use fdt::FDT_MAGIC;
+
+use crate::Error;
+
+/// A flattened device tree, as used for `ro_params`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceTree {
+    /// `/memreserve/` entries as (address, size)
+    pub reservations: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+    /// Set a property, replacing the value if it is already defined
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.properties.iter_mut().find(|p| p.name == name) {
+            Some(property) => property.value = value,
+            None => self.properties.push(Property {
+                name: name.to_string(),
+                value,
+            }),
+        }
+    }
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.name == name)
+    }
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        self.children.iter_mut().find(|c| c.name == name)
+    }
+    /// Get a child node, creating it if it doesn't exist yet
+    pub fn child_or_insert(&mut self, name: &str) -> &mut Node {
+        match self.children.iter().position(|c| c.name == name) {
+            Some(index) => &mut self.children[index],
+            None => {
+                self.children.push(Node::new(name));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+impl DeviceTree {
+    /// Find a node by its `/`-separated path from the root
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&mut self.root, |node, name| node.child_mut(name))
+    }
+    /// Apply a `path/to/property=value` setting, the value in DTS syntax.
+    /// The node must exist, the property is added if it doesn't.
+    pub fn apply_setting(&mut self, setting: &str) -> Result<(), Error> {
+        let invalid = || Error::InvalidDtbSetting(setting.to_string());
+        let split = setting.find('=').ok_or_else(invalid)?;
+        let (path, value) = (setting[..split].trim(), &setting[split + 1..]);
+        let (node_path, name) = match path.rfind('/') {
+            Some(split) => (&path[..split], &path[split + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let value = parse_value(value)?;
+        let node = self
+            .node_mut(node_path)
+            .ok_or_else(|| Error::DtbNodeNotFound(node_path.to_string()))?;
+        log::info!("Set {} = {}", path, format_value(&value));
+        node.set_property(name, value);
+        Ok(())
+    }
+}
+
+fn is_fdt(data: &[u8]) -> bool {
+    data.len() >= 4 && data[0..4] == FDT_MAGIC.to_be_bytes()
+}
+
+fn parse_dts_bytes(data: &[u8]) -> Result<DeviceTree, Error> {
+    let source = std::str::from_utf8(data)
+        .map_err(|_| Error::DtsParse(0, "source is not valid UTF-8".to_string()))?;
+    parse_dts(source)
+}
+
+/// Read `ro_params` from either a compiled blob or a `.dts` source
+pub fn read_ro_params(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if is_fdt(&data) {
+        return Ok(data);
+    }
+    log::trace!("Compile dts");
+    Ok(parse_dts_bytes(&data)?.to_fdt())
+}
+
+/// Decode a device tree from either a compiled blob or a `.dts` source
+pub fn read_device_tree(data: &[u8]) -> Result<DeviceTree, Error> {
+    if is_fdt(data) {
+        DeviceTree::from_fdt(data)
+    } else {
+        parse_dts_bytes(data)
+    }
+}
@@ -1,10 +1,13 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::ops::Range;
 
 use xmas_elf::program::{SegmentData, Type};
 use xmas_elf::ElfFile;
 
 use crate::chip::Chip;
+use crate::Error;
 
 pub struct FirmwareImage<'a> {
     pub entry: u32,
@@ -44,21 +47,25 @@ impl<'a> FirmwareImage<'a> {
             })
     }
     pub fn to_flash_bin(&self, chip: &dyn Chip) -> Vec<u8> {
-        let segs = self
-            .segments()
-            .filter_map(|segment| chip.get_flash_segment(segment))
-            .collect::<Vec<_>>();
-        let size = segs
-            .iter()
-            .fold(0, |len, i| len.max(i.addr + i.data.len() as u32));
-
-        let mut bin = Vec::new();
-        bin.resize(size as usize, 0xFF);
-        for s in segs {
-            bin[s.addr as usize..s.addr as usize + s.data.len()].copy_from_slice(&s.data);
-        }
-        bin
+        flash_bin(chip, self.segments())
+    }
+}
+
+/// Lay out the segments that land in flash from offset 0, gaps filled with 0xFF
+pub fn flash_bin<'a>(chip: &dyn Chip, segments: impl Iterator<Item = CodeSegment<'a>>) -> Vec<u8> {
+    let segs = segments
+        .filter_map(|segment| chip.get_flash_segment(segment))
+        .collect::<Vec<_>>();
+    let size = segs
+        .iter()
+        .fold(0, |len, i| len.max(i.addr + i.data.len() as u32));
+
+    let mut bin = Vec::new();
+    bin.resize(size as usize, 0xFF);
+    for s in segs {
+        bin[s.addr as usize..s.addr as usize + s.data.len()].copy_from_slice(&s.data);
     }
+    bin
 }
 
 #[derive(Debug, Ord, Eq)]
@@ -98,6 +105,17 @@ impl PartialOrd for CodeSegment<'_> {
     }
 }
 
+/// `range` widened to whole sectors of `sector_size` bytes
+pub fn sector_range(range: Range<u32>, sector_size: u32) -> Result<Range<u32>, Error> {
+    let start = range.start / sector_size * sector_size;
+    let end =
+        range.end.checked_add(sector_size - 1).ok_or_else(|| {
+            Error::AddressOverflow(format!("{:#x}..{:#x}", range.start, range.end))
+        })? / sector_size
+            * sector_size;
+    Ok(start..end)
+}
+
 /// A segment of data to write to the flash
 pub struct RomSegment<'a> {
     pub addr: u32,
@@ -108,6 +126,13 @@ impl<'a> RomSegment<'a> {
     pub fn size(&self) -> u32 {
         self.data.len() as u32
     }
+    /// The address right after the segment
+    pub fn end(&self) -> Result<u32, Error> {
+        u32::try_from(self.data.len())
+            .ok()
+            .and_then(|size| self.addr.checked_add(size))
+            .ok_or_else(|| Error::AddressOverflow(format!("Segment at {:#x}", self.addr)))
+    }
     pub fn from_vec(addr: u32, data: Vec<u8>) -> Self {
         RomSegment {
             addr,
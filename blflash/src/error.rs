@@ -19,16 +19,130 @@ pub enum Error {
     InvalidElf,
     #[error("elf image can not be ran from ram")]
     ElfNotRamLoadable,
-    #[error("chip not recognized")]
+    #[error("Chip not recognized from its boot ROM, pass --chip")]
     UnrecognizedChip,
-    #[error("flash chip not supported, flash id: {0:#x}")]
-    UnsupportedFlash(u8),
+    #[error("Boot ROM matches more than one chip ({0}), pass --chip")]
+    AmbiguousChip(String),
+    #[error(
+        "Unknown chip {0:?}, expected one of bl602, bl702, bl808, bl616 or a chip description file"
+    )]
+    UnknownChip(String),
+    #[error("Can't load {0:?} named by the chip description: {1}")]
+    ChipAsset(String, std::io::Error),
+    #[error("No {0} eflash_loader is bundled, pass a build with --eflash-loader")]
+    EflashLoaderNotBundled(String),
+    #[error("No {0} boot2 is bundled, pass a build with --boot2")]
+    Boot2NotBundled(String),
+    #[error("No {0} ro_params is bundled")]
+    RoParamsNotBundled(String),
+    #[error("eflash_loader image doesn't start with a boot header")]
+    InvalidEflashLoader,
+    #[error("flash chip not supported, flash id: {0:06x}")]
+    UnsupportedFlash(u32),
+    #[error("Invalid flash size {0:?}, expected e.g. 512K or 2M")]
+    InvalidFlashSize(String),
+    #[error("No partition table template fits a {0:#x} byte flash, pass --partition-cfg")]
+    NoPartitionTemplate(u32),
     #[error("ROM error {0:?}")]
     RomError(RomError),
     #[error("Parse error")]
     ParseError(#[from] deku::error::DekuError),
-    #[error("Parse toml error")]
+    #[error("Parse toml error: {0}")]
     TomlError(#[from] toml::de::Error),
+    #[error("Serialize toml error: {0}")]
+    TomlSerialize(String),
+    #[error("Partition table checksum mismatch: stored {0:#010x}, computed {1:#010x}")]
+    PartitionChecksum(u32, u32),
+    #[error("No valid partition table found")]
+    NoValidPartitionTable,
+    #[error("Invalid partition table:{}", format_partition_errors(.0))]
+    InvalidPartitionTable(Vec<PartitionError>),
+    #[error("Partition {0:?} not found")]
+    PartitionNotFound(String),
+    #[error("Partition {0:?} has no slot {1}")]
+    NoSuchSlot(String, usize),
+    #[error("Partition {0:?} is too small: {1} bytes don't fit in {2} bytes")]
+    PartitionTooSmall(String, usize, usize),
+    #[error("xz error: {0}")]
+    Xz(#[from] xz2::stream::Error),
+    #[error("Version {0:?} is longer than 15 bytes")]
+    VersionTooLong(String),
+    #[error("DTS parse error at line {0}: {1}")]
+    DtsParse(usize, String),
+    #[error("{0} parse error at record {1}: {2}")]
+    RecordParse(crate::records::RecordFormat, usize, String),
+    #[error("The {0} image has no data in the chip's flash")]
+    NoFlashData(crate::records::RecordFormat),
+    #[error("Invalid device tree: {0}")]
+    InvalidDtb(&'static str),
+    #[error("Device tree node {0:?} not found")]
+    DtbNodeNotFound(String),
+    #[error("Invalid setting {0:?}, expected path/to/property=value")]
+    InvalidDtbSetting(String),
+    #[error("Invalid key: {0}")]
+    InvalidKey(&'static str),
+    #[error("IV {0} was already used with this key, leave out --iv to pick a fresh one")]
+    IvReused(String),
+    #[error("The image on the device is not encrypted, give the IV with --iv")]
+    NoDeviceIv,
+    #[error("Unknown boot header field {0:?}")]
+    UnknownBootHeaderField(String),
+    #[error("Boot header field {0} = {1} doesn't fit in {2} bits")]
+    BootHeaderFieldRange(String, i64, u32),
+    #[error("Invalid value {1} for boot header field {0}")]
+    BootHeaderFieldValue(String, i64),
+    #[error("Invalid boot header override {0:?}, expected field=value")]
+    InvalidBootHeaderOverride(String),
+    #[error("Unknown core {0:?}")]
+    UnknownCore(String),
+    #[error("Invalid core image {0:?}, expected core=path")]
+    InvalidCoreImage(String),
+    #[error("Invalid segment {0:?}, expected address:file")]
+    InvalidRawSegment(String),
+    #[error("{0} overlaps {1}")]
+    SegmentOverlap(String, String),
+    #[error("{0} ends beyond the 32-bit address space")]
+    AddressOverflow(String),
+    #[error("{0} ends beyond the {1:#x} byte flash")]
+    SegmentPastFlashEnd(String, u32),
+    #[error("Flash config has a sector size of 0")]
+    InvalidSectorSize,
+    #[error("Signing and encryption are not supported for this chip's boot header")]
+    SecurityNotSupported,
+    #[error("Can't find the image behind the existing boot header")]
+    InvalidBootHeader,
+    #[error("Image offset {0:#x} is smaller than the {1} byte header")]
+    ImageOffsetTooSmall(usize, usize),
+    #[error(
+        "{0} already has a boot header, pass --reheader to apply the keys and header settings"
+    )]
+    ImageHasBootHeader(String),
+    #[error("Whole flash images are flashed as is, {0} can't apply to them")]
+    WholeFlashImage(String),
+}
+
+#[derive(Error, Debug)]
+pub enum PartitionError {
+    #[error("{0} is not aligned to 4 KiB")]
+    Unaligned(String),
+    #[error("{0} overlaps {1}")]
+    Overlap(String, String),
+    #[error("{0} ends beyond the flash size {1:#x}")]
+    OutOfFlash(String, u32),
+    #[error("{0} ends beyond the 32-bit address space")]
+    AddressOverflow(String),
+    #[error("name {0:?} is longer than 8 bytes")]
+    NameTooLong(String),
+    #[error("type {0} is used by both {1:?} and {2:?}")]
+    DuplicateType(u8, String, String),
+    #[error("mandatory entry {0:?} is missing")]
+    MissingEntry(&'static str),
+    #[error("active slot {1} of {0:?} is not used")]
+    UnusedActiveSlot(String, u8),
+}
+
+fn format_partition_errors(errors: &[PartitionError]) -> String {
+    errors.iter().map(|e| format!("\n  {}", e)).collect()
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -1,6 +1,9 @@
-use crate::chip::Chip;
+use crate::chip::{Chip, ChipType};
 use crate::Error;
-use crate::{connection::Connection, elf::RomSegment};
+use crate::{
+    connection::Connection,
+    elf::{sector_range, RomSegment},
+};
 use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use serial::{BaudRate, SerialPort};
 use sha2::{Digest, Sha256};
@@ -23,29 +26,60 @@ fn get_bar(len: u64) -> ProgressBar {
 pub struct Flasher {
     connection: Connection,
     boot_info: protocol::BootInfo,
+    chip_type: ChipType,
     chip: Box<dyn Chip>,
     flash_speed: BaudRate,
+    in_eflash_loader: bool,
+    eflash_loader: Vec<u8>,
 }
 
 impl Flasher {
+    /// Connect to the boot ROM, detecting the chip from its `BootInfo` when
+    /// `chip_type` is None. `eflash_loader` picks the loader for the chip.
     pub fn connect(
-        chip: impl Chip + 'static,
+        chip_type: Option<ChipType>,
         serial: impl SerialPort + 'static,
         initial_speed: BaudRate,
         flash_speed: BaudRate,
+        eflash_loader: impl FnOnce(&dyn Chip) -> Result<Vec<u8>, Error>,
     ) -> Result<Self, Error> {
-        let mut flasher = Flasher {
-            connection: Connection::new(serial),
-            boot_info: protocol::BootInfo::default(),
-            chip: Box::new(chip),
-            flash_speed,
+        let mut connection = Connection::new(serial);
+        connection.set_baud(initial_speed)?;
+        // Without a chip the plain handshake is used, which every ROM answers
+        let suffix = chip_type
+            .as_ref()
+            .map(|chip_type| chip_type.chip().handshake_suffix().to_vec())
+            .unwrap_or_default();
+        Self::start_connection(&mut connection, &suffix)?;
+        connection.set_timeout(Duration::from_secs(10))?;
+        let mut boot_info = BootRom(&mut connection).get_boot_info()?;
+
+        let chip_type = match chip_type {
+            Some(chip_type) => chip_type,
+            None => {
+                let chip_type = ChipType::detect(boot_info.bootrom_version, &boot_info.otp_info)?;
+                log::info!("Detected {}", chip_type.chip().name());
+                // Start over with the handshake the chip expects
+                let suffix = chip_type.chip().handshake_suffix().to_vec();
+                if !suffix.is_empty() {
+                    Self::start_connection(&mut connection, &suffix)?;
+                    boot_info = BootRom(&mut connection).get_boot_info()?;
+                }
+                chip_type
+            }
         };
-        flasher.connection.set_baud(initial_speed)?;
-        flasher.start_connection()?;
-        flasher.connection.set_timeout(Duration::from_secs(10))?;
-        flasher.boot_info = flasher.boot_rom().get_boot_info()?;
-
-        Ok(flasher)
+        let chip = chip_type.chip();
+        let eflash_loader = eflash_loader(&*chip)?;
+
+        Ok(Flasher {
+            connection,
+            boot_info,
+            chip_type,
+            chip,
+            flash_speed,
+            in_eflash_loader: false,
+            eflash_loader,
+        })
     }
 
     pub fn into_inner(self) -> Connection {
@@ -56,11 +90,28 @@ impl Flasher {
         &self.boot_info
     }
 
+    pub fn chip(&self) -> &dyn Chip {
+        &*self.chip
+    }
+
+    pub fn chip_type(&self) -> ChipType {
+        self.chip_type.clone()
+    }
+
+    /// Write the segments, skipping those whose hash matches unless `force`
+    /// is set. With `diff`, only the sectors whose hash differs are rewritten.
+    /// Erases are aligned to `sector_size`, from the flash config of the boot
+    /// header.
     pub fn load_segments<'a>(
         &'a mut self,
         force: bool,
+        diff: bool,
+        sector_size: u32,
         segments: impl Iterator<Item = RomSegment<'a>>,
     ) -> Result<(), Error> {
+        if sector_size == 0 {
+            return Err(Error::InvalidSectorSize);
+        }
         self.load_eflash_loader()?;
 
         for segment in segments {
@@ -81,36 +132,16 @@ impl Flasher {
                 }
             }
 
-            log::info!(
-                "Erase flash addr: {:x} size: {}",
-                segment.addr,
-                segment.size()
-            );
-            self.eflash_loader()
-                .flash_erase(segment.addr, segment.addr + segment.size())?;
-
-            let mut reader = Cursor::new(&segment.data);
-            let mut cur = segment.addr;
-
-            let start = Instant::now();
             log::info!("Program flash... {:x}", local_hash);
-            let pb = get_bar(segment.size() as u64);
-            loop {
-                let size = self.eflash_loader().flash_program(cur, &mut reader)?;
-                // log::trace!("program {:x} {:x}", cur, size);
-                cur += size;
-                pb.inc(size as u64);
-                if size == 0 {
-                    break;
+            if diff && !force {
+                for range in self.changed_sectors(&segment, sector_size)? {
+                    let data = &segment.data[(range.start - segment.addr) as usize
+                        ..(range.end - segment.addr) as usize];
+                    self.write_flash(range.start, data, sector_size)?;
                 }
+            } else {
+                self.write_flash(segment.addr, &segment.data, sector_size)?;
             }
-            pb.finish_and_clear();
-            let elapsed = start.elapsed();
-            log::info!(
-                "Program done {:?} {}/s",
-                elapsed,
-                HumanBytes((segment.size() as f64 / elapsed.as_millis() as f64 * 1000.0) as u64)
-            );
 
             let sha256 = self
                 .eflash_loader()
@@ -126,6 +157,74 @@ impl Flasher {
         Ok(())
     }
 
+    /// Parts of the segment, one or more sectors long, that differ from the
+    /// flash
+    fn changed_sectors(
+        &mut self,
+        segment: &RomSegment,
+        sector_size: u32,
+    ) -> Result<Vec<Range<u32>>, Error> {
+        let end = segment.end()?;
+        let sectors = sector_range(segment.addr..end, sector_size)?;
+        let count = (sectors.end - sectors.start) / sector_size;
+
+        let pb = get_bar(segment.size() as u64);
+        let changed = changed_ranges(segment.addr..end, sector_size, |range| {
+            let data = &segment.data
+                [(range.start - segment.addr) as usize..(range.end - segment.addr) as usize];
+            let sha256 = self
+                .eflash_loader()
+                .sha256_read(range.start, range.end - range.start)?;
+            pb.inc(data.len() as u64);
+            Ok(sha256[..] != Sha256::digest(data)[..])
+        })?;
+        pb.finish_and_clear();
+
+        let mut changed_count = 0;
+        for range in &changed {
+            changed_count += sector_range(range.clone(), sector_size)?.len() as u32 / sector_size;
+        }
+        log::info!("{} of {} sectors changed", changed_count, count);
+        Ok(changed)
+    }
+
+    /// Erase the sectors under `data` and program it at `addr`. The rest of
+    /// partially covered sectors is read first and written back.
+    fn write_flash(&mut self, addr: u32, data: &[u8], sector_size: u32) -> Result<(), Error> {
+        let (erase, buf) = sector_write(addr, data, sector_size, |range| self.read_flash(range))?;
+
+        log::info!(
+            "Erase flash addr: {:x} size: {}",
+            erase.start,
+            erase.end - erase.start
+        );
+        self.eflash_loader().flash_erase(erase.clone())?;
+
+        let data = &buf[..];
+        let mut reader = Cursor::new(data);
+        let mut cur = erase.start;
+
+        let start = Instant::now();
+        let pb = get_bar(data.len() as u64);
+        loop {
+            let size = self.eflash_loader().flash_program(cur, &mut reader)?;
+            // log::trace!("program {:x} {:x}", cur, size);
+            cur += size;
+            pb.inc(size as u64);
+            if size == 0 {
+                break;
+            }
+        }
+        pb.finish_and_clear();
+        let elapsed = start.elapsed();
+        log::info!(
+            "Program done {:?} {}/s",
+            elapsed,
+            HumanBytes((data.len() as f64 / elapsed.as_millis() as f64 * 1000.0) as u64)
+        );
+        Ok(())
+    }
+
     pub fn check_segments<'a>(
         &'a mut self,
         segments: impl Iterator<Item = RomSegment<'a>>,
@@ -162,6 +261,10 @@ impl Flasher {
             let data = self
                 .eflash_loader()
                 .flash_read(cur, (range.end - cur).min(BLOCK_SIZE as u32))?;
+            // An empty answer would never get to the end
+            if data.is_empty() {
+                return Err(Error::RespError);
+            }
             writer.write_all(&data)?;
             cur += data.len() as u32;
             pb.inc(data.len() as u64);
@@ -171,11 +274,50 @@ impl Flasher {
         Ok(())
     }
 
+    /// Detect the flash size from the capacity byte of its JEDEC ID, the
+    /// chip's default if that byte isn't a size
+    pub fn flash_size(&mut self) -> Result<u32, Error> {
+        self.load_eflash_loader()?;
+
+        let id = self.eflash_loader().flash_read_jedec_id()?;
+        let id = u32::from_be_bytes([0, id[0], id[1], id[2]]);
+        let size = match jedec_flash_size(id) {
+            Some(size) => size,
+            None => {
+                let size = self.chip.default_flash_size();
+                log::warn!(
+                    "Can't size JEDEC ID {:06x}, assuming {}, see --flash-size",
+                    id,
+                    HumanBytes(size as u64)
+                );
+                return Ok(size);
+            }
+        };
+        log::info!(
+            "Flash size: {} (JEDEC ID {:06x})",
+            HumanBytes(size as u64),
+            id
+        );
+
+        Ok(size)
+    }
+
+    pub fn read_flash(&mut self, range: Range<u32>) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(range.len());
+        self.dump_flash(range, &mut data)?;
+        Ok(data)
+    }
+
     pub fn load_eflash_loader(&mut self) -> Result<(), Error> {
-        let input = self.chip.get_eflash_loader().to_vec();
+        if self.in_eflash_loader {
+            return Ok(());
+        }
+        let input = self.eflash_loader.clone();
         let len = input.len();
         let mut reader = Cursor::new(input);
-        self.boot_rom().load_boot_header(&mut reader)?;
+        let boot_header_len = self.chip.boot_header_len();
+        self.boot_rom()
+            .load_boot_header(&mut reader, boot_header_len)?;
         self.boot_rom().load_segment_header(&mut reader)?;
 
         let start = Instant::now();
@@ -200,7 +342,8 @@ impl Flasher {
         self.boot_rom().run_image()?;
         sleep(Duration::from_millis(500));
         self.connection.set_baud(self.flash_speed)?;
-        self.handshake()?;
+        Self::handshake(&mut self.connection, &[])?;
+        self.in_eflash_loader = true;
 
         log::info!("Entered eflash_loader");
 
@@ -208,6 +351,7 @@ impl Flasher {
     }
 
     pub fn reset(&mut self) -> Result<(), Error> {
+        self.in_eflash_loader = false;
         Ok(self.connection.reset()?)
     }
 
@@ -219,34 +363,36 @@ impl Flasher {
         EflashLoader(&mut self.connection)
     }
 
-    fn handshake(&mut self) -> Result<(), Error> {
-        self.connection
-            .with_timeout(Duration::from_millis(200), |connection| {
-                let len = connection.calc_duration_length(Duration::from_millis(5));
-                log::trace!("5ms send count {}", len);
-                let data: Vec<u8> = std::iter::repeat(0x55u8).take(len).collect();
-                let start = Instant::now();
-                connection.write_all(&data)?;
-                connection.flush()?;
-                log::trace!("handshake sent elapsed {:?}", start.elapsed());
-                sleep(Duration::from_millis(200));
-
-                for _ in 0..5 {
-                    if connection.read_response(0).is_ok() {
-                        return Ok(());
-                    }
+    /// Send the 0x55 sync bytes followed by `suffix`, which some boot ROMs
+    /// expect before they answer
+    fn handshake(connection: &mut Connection, suffix: &[u8]) -> Result<(), Error> {
+        connection.with_timeout(Duration::from_millis(200), |connection| {
+            let len = connection.calc_duration_length(Duration::from_millis(5));
+            log::trace!("5ms send count {}", len);
+            let mut data: Vec<u8> = std::iter::repeat(0x55u8).take(len).collect();
+            data.extend_from_slice(suffix);
+            let start = Instant::now();
+            connection.write_all(&data)?;
+            connection.flush()?;
+            log::trace!("handshake sent elapsed {:?}", start.elapsed());
+            sleep(Duration::from_millis(200));
+
+            for _ in 0..5 {
+                if connection.read_response(0).is_ok() {
+                    return Ok(());
                 }
+            }
 
-                Err(Error::Timeout)
-            })
+            Err(Error::Timeout)
+        })
     }
 
-    fn start_connection(&mut self) -> Result<(), Error> {
+    fn start_connection(connection: &mut Connection, suffix: &[u8]) -> Result<(), Error> {
         log::info!("Start connection...");
-        self.connection.reset_to_flash()?;
+        connection.reset_to_flash()?;
         for i in 1..=10 {
-            self.connection.flush()?;
-            if self.handshake().is_ok() {
+            connection.flush()?;
+            if Self::handshake(connection, suffix).is_ok() {
                 log::info!("Connection Succeed");
                 return Ok(());
             } else {
@@ -257,6 +403,83 @@ impl Flasher {
     }
 }
 
+/// The sectors to erase to write `data` at `addr`, and what to program from
+/// their start: `data` with the rest of the partially covered sectors around
+/// it, which `read` gets from the flash
+fn sector_write(
+    addr: u32,
+    data: &[u8],
+    sector_size: u32,
+    mut read: impl FnMut(Range<u32>) -> Result<Vec<u8>, Error>,
+) -> Result<(Range<u32>, Vec<u8>), Error> {
+    let end = RomSegment::from_slice(addr, data).end()?;
+    let erase = sector_range(addr..end, sector_size)?;
+    let mut buf = Vec::with_capacity(erase.len());
+    if erase.start < addr || end < erase.end {
+        let head = read(erase.start..addr)?;
+        let tail = read(end..erase.end)?;
+        if head.iter().chain(&tail).any(|byte| *byte != 0xff) {
+            log::warn!(
+                "Erasing {:#x}..{:#x} touches data outside {:#x}..{:#x}, preserving it",
+                erase.start,
+                erase.end,
+                addr,
+                end
+            );
+        }
+        buf.extend(head);
+        buf.extend_from_slice(data);
+        buf.extend(tail);
+    } else {
+        buf.extend_from_slice(data);
+    }
+    Ok((erase, buf))
+}
+
+/// The eflash_loader's erase command for `range`, which takes the last byte
+/// rather than the end. None when there is nothing to erase.
+fn erase_command(range: Range<u32>) -> Option<protocol::FlashErase> {
+    if range.is_empty() {
+        return None;
+    }
+    Some(protocol::FlashErase {
+        start: range.start,
+        end: range.end - 1,
+    })
+}
+
+/// The parts of `range`, each one or more sectors long and clipped to
+/// `range`, of the sectors `changed` reports a difference in. Adjacent
+/// changed sectors are merged into one part.
+fn changed_ranges(
+    range: Range<u32>,
+    sector_size: u32,
+    mut changed: impl FnMut(Range<u32>) -> Result<bool, Error>,
+) -> Result<Vec<Range<u32>>, Error> {
+    let sectors = sector_range(range.clone(), sector_size)?;
+    let mut ranges: Vec<Range<u32>> = Vec::new();
+    for sector in (sectors.start..sectors.end).step_by(sector_size as usize) {
+        let part = sector.max(range.start)..(sector + sector_size).min(range.end);
+        if !changed(part.clone())? {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == part.start => last.end = part.end,
+            _ => ranges.push(part),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Flash vendors encode the capacity as log2 of the size in bytes in the last
+/// byte of the JEDEC ID
+fn jedec_flash_size(id: u32) -> Option<u32> {
+    match id & 0xff {
+        capacity @ 0x10..=0x1f => Some(1 << capacity),
+        _ => None,
+    }
+}
+
 pub struct BootRom<'a>(&'a mut Connection);
 
 impl<'a> BootRom<'a> {
@@ -270,8 +493,9 @@ impl<'a> BootRom<'a> {
         Ok(())
     }
 
-    pub fn load_boot_header(&mut self, reader: &mut impl Read) -> Result<(), Error> {
-        let mut boot_header = vec![0u8; protocol::LOAD_BOOT_HEADER_LEN];
+    /// Send the first `len` bytes of the image, the chip's boot header
+    pub fn load_boot_header(&mut self, reader: &mut impl Read, len: usize) -> Result<(), Error> {
+        let mut boot_header = vec![0u8; len];
         reader.read_exact(&mut boot_header)?;
         self.0.command(protocol::LoadBootHeader { boot_header })?;
         Ok(())
@@ -338,8 +562,15 @@ impl<'a> EflashLoader<'a> {
         Ok(size as u32)
     }
 
-    pub fn flash_erase(&mut self, start: u32, end: u32) -> Result<(), Error> {
-        self.0.command(protocol::FlashErase { start, end })?;
+    pub fn flash_read_jedec_id(&mut self) -> Result<[u8; 3], Error> {
+        let id = self.0.command(protocol::FlashReadJedecId {})?.id;
+        Ok([id[0], id[1], id[2]])
+    }
+
+    pub fn flash_erase(&mut self, range: Range<u32>) -> Result<(), Error> {
+        if let Some(command) = erase_command(range) {
+            self.0.command(command)?;
+        }
 
         Ok(())
     }
@@ -349,7 +580,6 @@ mod protocol {
     use crate::connection::{Command, Response};
     use deku::prelude::*;
 
-    pub const LOAD_BOOT_HEADER_LEN: usize = 176;
     pub const LOAD_SEGMENT_HEADER_LEN: usize = 16;
 
     #[derive(Debug, DekuWrite, Default)]
@@ -366,13 +596,15 @@ mod protocol {
     pub struct BootInfo {
         pub len: u16,
         pub bootrom_version: u32,
-        pub otp_info: [u8; 16],
+        /// eFuse info, its length depends on the chip
+        #[deku(count = "(*len as usize).saturating_sub(4)")]
+        pub otp_info: Vec<u8>,
     }
     impl_command!(0x10, BootInfoReq, BootInfo);
 
     #[derive(Debug, DekuWrite, Default)]
     pub struct LoadBootHeader {
-        // length must be 176
+        // length must be the chip's `boot_header_len`
         pub boot_header: Vec<u8>,
     }
     impl_command!(0x11, LoadBootHeader);
@@ -423,6 +655,16 @@ mod protocol {
     }
     impl_command!(0x32, FlashRead, FlashReadResp);
 
+    #[derive(Debug, DekuWrite, Default)]
+    pub struct FlashReadJedecId {}
+    #[derive(Debug, DekuRead)]
+    #[deku(magic = b"\x04\x00")]
+    pub struct FlashReadJedecIdResp {
+        /// Manufacturer, memory type, capacity and a padding byte
+        pub id: [u8; 4],
+    }
+    impl_command!(0x36, FlashReadJedecId, FlashReadJedecIdResp);
+
     #[derive(Debug, DekuWrite, Default)]
     pub struct Sha256Read {
         pub addr: u32,
@@ -436,3 +678,94 @@ mod protocol {
     }
     impl_command!(0x3d, Sha256Read, Sha256ReadResp);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `data` at `addr` to `flash` with 0x100 byte sectors, erasing
+    /// and programming like `write_flash` does
+    fn write(flash: &mut [u8], addr: u32, data: &[u8]) -> Range<u32> {
+        let (erase, buf) = sector_write(addr, data, 0x100, |range| {
+            Ok(flash[range.start as usize..range.end as usize].to_vec())
+        })
+        .unwrap();
+        let erase_range = erase.start as usize..erase.end as usize;
+        assert_eq!(buf.len(), erase_range.len());
+        flash[erase_range].copy_from_slice(&buf);
+        erase
+    }
+
+    #[test]
+    fn sector_write_keeps_unaligned_head_and_tail() {
+        let mut flash = (0..0x400).map(|i| i as u8).collect::<Vec<_>>();
+        let before = flash.clone();
+        let erase = write(&mut flash, 0x180, &[0xaa; 0x100]);
+        assert_eq!(erase, 0x100..0x300);
+        assert_eq!(flash[..0x180], before[..0x180]);
+        assert!(flash[0x180..0x280].iter().all(|byte| *byte == 0xaa));
+        assert_eq!(flash[0x280..], before[0x280..]);
+    }
+
+    #[test]
+    fn sector_write_keeps_other_segments_in_the_sector() {
+        let mut flash = vec![0xff; 0x400];
+        write(&mut flash, 0x110, &[1; 0x10]);
+        write(&mut flash, 0x140, &[2; 0x20]);
+        write(&mut flash, 0x1f0, &[3; 0x10]);
+        assert_eq!(flash[0x110..0x120], [1; 0x10]);
+        assert_eq!(flash[0x140..0x160], [2; 0x20]);
+        assert_eq!(flash[0x1f0..0x200], [3; 0x10]);
+        let written = [0x110..0x120, 0x140..0x160, 0x1f0..0x200];
+        assert!((0..0x400)
+            .filter(|i| !written.iter().any(|range| range.contains(i)))
+            .all(|i| flash[i] == 0xff));
+    }
+
+    #[test]
+    fn sector_write_aligned_reads_nothing() {
+        let (erase, buf) = sector_write(0x100, &[1; 0x200], 0x100, |_| {
+            panic!("aligned writes don't read the flash")
+        })
+        .unwrap();
+        assert_eq!(erase, 0x100..0x300);
+        assert_eq!(buf, vec![1; 0x200]);
+    }
+
+    #[test]
+    fn erase_command_takes_the_last_byte() {
+        let command = erase_command(0x1000..0x3000).unwrap();
+        assert_eq!((command.start, command.end), (0x1000, 0x2fff));
+        assert!(erase_command(0x1000..0x1000).is_none());
+    }
+
+    #[test]
+    fn changed_ranges_merge_adjacent_sectors() {
+        // Sectors 0x1000, 0x2000 and 0x4000 changed, 0x3000 didn't
+        let changed = [0x1000, 0x2000, 0x4000];
+        let mut asked = Vec::new();
+        let ranges = changed_ranges(0x1800..0x4800, 0x1000, |part| {
+            asked.push(part.clone());
+            Ok(changed.contains(&(part.start & !0xfff)))
+        })
+        .unwrap();
+        assert_eq!(
+            asked,
+            vec![
+                0x1800..0x2000,
+                0x2000..0x3000,
+                0x3000..0x4000,
+                0x4000..0x4800
+            ]
+        );
+        assert_eq!(ranges, vec![0x1800..0x3000, 0x4000..0x4800]);
+    }
+
+    #[test]
+    fn changed_ranges_within_one_sector() {
+        let ranges = changed_ranges(0x1010..0x1020, 0x1000, |_| Ok(true)).unwrap();
+        assert_eq!(ranges, vec![0x1010..0x1020]);
+        let ranges = changed_ranges(0x1010..0x1020, 0x1000, |_| Ok(false)).unwrap();
+        assert!(ranges.is_empty());
+    }
+}
@@ -1,17 +1,66 @@
+use super::{EfuseCfg, EncryptKey, SignKey, SIGNATURE_BLOCKS_LEN};
 use crate::Error;
 use byteorder::{NativeEndian, ReadBytesExt};
 use deku::prelude::*;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::Cursor;
 
-#[derive(Debug, Deserialize, Default, Clone)]
-pub struct BootHeaderCfgFile {
+/// Every boot header starts with this magic
+pub const BOOT_HEADER_MAGIC: &[u8] = b"BFNP";
+
+/// Whether `image` already starts with a boot header
+pub fn has_boot_header(image: &[u8]) -> bool {
+    image.starts_with(BOOT_HEADER_MAGIC)
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct BootHeaderCfgFile<T = BootHeaderCfg> {
     #[serde(rename = "BOOTHEADER_CFG")]
-    pub boot_header_cfg: BootHeaderCfg,
+    pub boot_header_cfg: T,
+}
+
+/// Boot header put in front of every image the ROM or boot2 loads, its
+/// layout depends on the chip
+pub trait BootHeader {
+    /// Offset of the image booted by the ROM from its header, boot2 or the
+    /// firmware when flashed without boot2
+    fn boot2_offset(&self) -> u32;
+    /// Offset of the firmware from its header in the FW partition
+    fn fw_offset(&self) -> u32;
+    /// Size of the flash's erase sector in bytes, from the flash config
+    fn sector_size(&self) -> u32;
+    /// Sign every image made from now on for secure boot
+    fn set_sign_key(&mut self, key: SignKey) -> Result<(), Error>;
+    /// Encrypt every image made from now on for flash encryption
+    fn set_encrypt_key(&mut self, key: EncryptKey) -> Result<(), Error>;
+    /// eFuse values the device needs to boot images made with these keys
+    fn efuse_cfg(&self) -> EfuseCfg;
+    /// Offset of the IV block from the header in encrypted images, behind
+    /// the header and the signature of `signed` ones
+    fn iv_offset(&self, signed: bool) -> Result<usize, Error>;
+    /// Boot every image made from now on on `core`, for chips with more
+    /// than one CPU
+    fn select_core(&mut self, core: &str) -> Result<(), Error>;
+    /// Put the header in front of `image`, which starts `offset` bytes after it
+    fn make_image(&mut self, offset: usize, image: Vec<u8>) -> Result<Vec<u8>, Error>;
+    /// Like `make_image`, but an image that already has a header is
+    /// flashed as is
+    fn wrap_image(&mut self, offset: usize, image: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if has_boot_header(&image) {
+            return Ok(image);
+        }
+        self.make_image(offset, image)
+    }
+    /// The image behind the header of `image`, which was made with this
+    /// chip's header layout
+    fn strip_header<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], Error>;
+    /// Render in the `efuse_bootheader_cfg.conf` format
+    fn to_toml(&self) -> Result<String, Error>;
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+/// SPI flash config, shared by the boot headers of every chip
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
 pub struct FlashCfg {
     flashcfg_magic_code: u32,
     // 12
@@ -118,7 +167,7 @@ pub struct FlashCfg {
     flashcfg_crc32: u32,
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
 pub struct ClkCfg {
     // 100
     clkcfg_magic_code: u32,
@@ -139,7 +188,7 @@ pub struct ClkCfg {
 
 // NOTE: the order is reversed here
 // see: https://github.com/sharksforarms/deku/issues/134
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
 pub struct BootCfg {
     // 116
     #[deku(bits = 2)]
@@ -191,7 +240,7 @@ pub struct BootCfg {
     _unused3: [u8; 8],
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default, Clone)]
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
 pub struct BootHeaderCfg {
     magic_code: u32,
     revision: u32,
@@ -208,9 +257,131 @@ pub struct BootHeaderCfg {
     // 172
     #[deku(update = "self.checksum()")]
     crc32: u32,
+
+    /// See `BootHeader::boot2_offset`
+    #[serde(default = "default_boot2_offset")]
+    #[deku(skip)]
+    boot2_offset: u32,
+    /// See `BootHeader::fw_offset`
+    #[serde(default = "default_fw_offset")]
+    #[deku(skip)]
+    fw_offset: u32,
+
+    /// Sign images with this key, see `set_sign_key`
+    #[serde(skip)]
+    #[deku(skip)]
+    sign_key: Option<SignKey>,
+    /// Encrypt images with this key, see `set_encrypt_key`
+    #[serde(skip)]
+    #[deku(skip)]
+    encrypt_key: Option<EncryptKey>,
+}
+
+/// Width of the bitfields in `BootCfg`, other fields are checked by their type
+pub const BITFIELDS: &[(&str, u32)] = &[
+    ("sign", 2),
+    ("encrypt_type", 2),
+    ("key_sel", 2),
+    ("no_segment", 1),
+    ("cache_enable", 1),
+    ("notload_in_bootrom", 1),
+    ("aes_region_lock", 1),
+    ("cache_way_disable", 4),
+    ("crc_ignore", 1),
+    ("hash_ignore", 1),
+];
+
+pub(crate) fn default_boot2_offset() -> u32 {
+    0x2000
+}
+
+pub(crate) fn default_fw_offset() -> u32 {
+    0x1000
+}
+
+impl<T: DeserializeOwned> BootHeaderCfgFile<T> {
+    /// Parse `efuse_bootheader_cfg.conf`, then apply `field = value` overrides
+    /// to `[BOOTHEADER_CFG]` in order. Fields of sub-tables are named
+    /// `table.field`, `bitfields` gives the width of fields narrower than
+    /// their type.
+    pub fn from_toml_with_overrides(
+        data: &[u8],
+        overrides: &[(String, i64)],
+        bitfields: &[(&str, u32)],
+    ) -> Result<Self, Error> {
+        Ok(Self::apply_overrides(data, overrides, bitfields)?.try_into()?)
+    }
+    /// Like `from_toml_with_overrides`, but returns the config before it is
+    /// converted to `T`
+    pub(crate) fn apply_overrides(
+        data: &[u8],
+        overrides: &[(String, i64)],
+        bitfields: &[(&str, u32)],
+    ) -> Result<toml::Value, Error> {
+        let mut cfg: toml::Value = toml::from_slice(data)?;
+        cfg.clone().try_into::<Self>()?;
+        if let Some(table) = cfg
+            .get_mut("BOOTHEADER_CFG")
+            .and_then(|table| table.as_table_mut())
+        {
+            for (field, value) in overrides {
+                let unknown = || Error::UnknownBootHeaderField(field.clone());
+                let mut path: Vec<_> = field.split('.').collect();
+                let name = path.pop().unwrap();
+                let mut parent = &mut *table;
+                for key in path {
+                    parent = parent
+                        .get_mut(key)
+                        .and_then(|table| table.as_table_mut())
+                        .ok_or_else(unknown)?;
+                }
+                let known =
+                    parent.contains_key(name) || field == "boot2_offset" || field == "fw_offset";
+                if !known {
+                    return Err(unknown());
+                }
+                if let Some((_, bits)) = bitfields.iter().find(|(bitfield, _)| *bitfield == name) {
+                    if *value < 0 || *value >= 1 << bits {
+                        return Err(Error::BootHeaderFieldRange(field.clone(), *value, *bits));
+                    }
+                }
+                match parent.insert(name.to_string(), toml::Value::Integer(*value)) {
+                    Some(toml::Value::Integer(old)) => log::info!(
+                        "Boot header override: {} = {:#x}, was {:#x}",
+                        field,
+                        value,
+                        old
+                    ),
+                    _ => log::info!("Boot header override: {} = {:#x}", field, value),
+                }
+                // Catches values that don't fit the type of the field
+                toml::Value::Table(table.clone())
+                    .try_into::<T>()
+                    .map_err(|_| Error::BootHeaderFieldValue(field.clone(), *value))?;
+            }
+            if !overrides.is_empty() {
+                log::debug!(
+                    "Effective boot header:\n{}",
+                    toml::to_string(table).unwrap_or_default()
+                );
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+impl<T: Serialize> BootHeaderCfgFile<T> {
+    /// Render in the `efuse_bootheader_cfg.conf` format
+    pub fn to_toml(&self) -> Result<String, Error> {
+        toml::to_string(self).map_err(|e| Error::TomlSerialize(e.to_string()))
+    }
 }
 
 impl FlashCfg {
+    /// `sector_size` is in KiB
+    pub fn sector_size(&self) -> u32 {
+        self.sector_size as u32 * 1024
+    }
     fn checksum(&self) -> u32 {
         let data = self.to_bytes().unwrap();
         crc::crc32::checksum_ieee(&data[4..data.len() - 4])
@@ -241,10 +412,57 @@ impl BootHeaderCfg {
         self.boot_cfg.hash_7 = reader.read_u32::<NativeEndian>()?;
         Ok(())
     }
-    pub fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
+}
+
+impl BootHeader for BootHeaderCfg {
+    fn boot2_offset(&self) -> u32 {
+        self.boot2_offset
+    }
+    fn fw_offset(&self) -> u32 {
+        self.fw_offset
+    }
+    fn sector_size(&self) -> u32 {
+        self.flash_cfg.sector_size()
+    }
+    fn set_sign_key(&mut self, key: SignKey) -> Result<(), Error> {
+        self.boot_cfg.sign = 1;
+        self.sign_key = Some(key);
+        Ok(())
+    }
+    fn set_encrypt_key(&mut self, key: EncryptKey) -> Result<(), Error> {
+        self.boot_cfg.encrypt_type = key.encrypt_type();
+        self.encrypt_key = Some(key);
+        Ok(())
+    }
+    fn efuse_cfg(&self) -> EfuseCfg {
+        let mut efuse_cfg = EfuseCfg::default();
+        if let Some(key) = &self.sign_key {
+            efuse_cfg.set_public_key(&key.public_key());
+        }
+        if let Some(key) = &self.encrypt_key {
+            efuse_cfg.set_aes_key(key.key());
+        }
+        efuse_cfg
+    }
+    fn iv_offset(&self, signed: bool) -> Result<usize, Error> {
+        let signature_len = if signed { SIGNATURE_BLOCKS_LEN } else { 0 };
+        Ok(self.to_bytes()?.len() + signature_len)
+    }
+    fn select_core(&mut self, core: &str) -> Result<(), Error> {
+        Err(Error::UnknownCore(core.to_string()))
+    }
+    fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
         let binlen = ((image.len() + 15) / 16) * 16;
         image.resize(binlen, 0xFF);
-        let hash = Sha256::digest(&image);
+        // The hash covers the IV block and the encrypted image
+        let mut iv_block = Vec::new();
+        if let Some(key) = &mut self.encrypt_key {
+            iv_block = key.encrypt(&mut image)?;
+        }
+        let hash = Sha256::new()
+            .chain_update(&iv_block)
+            .chain_update(&image)
+            .finalize();
         self.update_sha256(&hash[..])?;
         self.boot_cfg.img_len = image.len() as u32;
         self.flash_cfg.update()?;
@@ -252,10 +470,38 @@ impl BootHeaderCfg {
         self.update()?;
 
         let mut header = self.to_bytes()?;
+        if let Some(key) = &self.sign_key {
+            header.append(&mut key.signature_blocks(&hash)?);
+        }
+        header.append(&mut iv_block);
+        if header.len() > offset {
+            return Err(Error::ImageOffsetTooSmall(offset, header.len()));
+        }
 
         header.resize(offset, 0xff);
         header.append(&mut image);
 
         Ok(header)
     }
+    fn strip_header<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], Error> {
+        // img_len follows the flash and clock configs and the bitfield word,
+        // the image ends the file
+        let at = 8 + self.flash_cfg.to_bytes()?.len() + self.clk_cfg.to_bytes()?.len() + 4;
+        let img_len = image
+            .get(at..)
+            .and_then(|mut field| field.read_u32::<NativeEndian>().ok())
+            .ok_or(Error::InvalidBootHeader)?;
+        let start = image
+            .len()
+            .checked_sub(img_len as usize)
+            .filter(|start| *start > at)
+            .ok_or(Error::InvalidBootHeader)?;
+        Ok(&image[start..])
+    }
+    fn to_toml(&self) -> Result<String, Error> {
+        BootHeaderCfgFile {
+            boot_header_cfg: self,
+        }
+        .to_toml()
+    }
 }
@@ -0,0 +1,71 @@
+use super::{aes_mode, public_key_hash};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+/// First key slot holding the public key hash
+const PK_HASH_SLOT: usize = 0;
+/// First key slot holding the flash encryption key
+const AES_KEY_SLOT: usize = 2;
+
+#[derive(Debug, Default, Clone)]
+pub struct KeySlot {
+    /// Slot contents as little-endian words
+    pub words: [u32; 4],
+    /// Only the hardware may read the slot
+    pub read_lock: bool,
+}
+
+/// eFuse values matching the keys used for an image, rendered as the
+/// `[EFUSE_CFG]` section of `efuse_bootheader_cfg.conf`
+#[derive(Debug, Default, Clone)]
+pub struct EfuseCfg {
+    pub sf_aes_mode: u8,
+    pub sboot_sign_mode: u8,
+    pub key_slots: BTreeMap<usize, KeySlot>,
+}
+
+impl EfuseCfg {
+    /// Store the hash of a signing public key starting at key slot 0
+    pub fn set_public_key(&mut self, public_key: &[u8]) {
+        self.sboot_sign_mode = 1;
+        self.set_key_slots(PK_HASH_SLOT, &public_key_hash(public_key), false);
+    }
+    /// Store a flash encryption key starting at key slot 2
+    pub fn set_aes_key(&mut self, key: &[u8]) {
+        self.sf_aes_mode = aes_mode(key);
+        self.set_key_slots(AES_KEY_SLOT, key, true);
+    }
+    fn set_key_slots(&mut self, first_slot: usize, data: &[u8], read_lock: bool) {
+        for (index, chunk) in data.chunks(16).enumerate() {
+            let mut words = [0; 4];
+            for (word, bytes) in words.iter_mut().zip(chunk.chunks(4)) {
+                let mut le = [0; 4];
+                le[..bytes.len()].copy_from_slice(bytes);
+                *word = u32::from_le_bytes(le);
+            }
+            self.key_slots
+                .insert(first_slot + index, KeySlot { words, read_lock });
+        }
+    }
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "[EFUSE_CFG]").unwrap();
+        writeln!(out, "#2bits").unwrap();
+        writeln!(out, "ef_sf_aes_mode = {}", self.sf_aes_mode).unwrap();
+        writeln!(out, "#2bits").unwrap();
+        writeln!(out, "ef_sboot_sign_mode = {}", self.sboot_sign_mode).unwrap();
+        for (slot, key_slot) in &self.key_slots {
+            for (index, word) in key_slot.words.iter().enumerate() {
+                writeln!(out, "ef_key_slot_{}_w{} = 0x{:08x}", slot, index, word).unwrap();
+            }
+        }
+        // Keys must not be changed once written, the AES key not even read back
+        for (slot, key_slot) in &self.key_slots {
+            writeln!(out, "wr_lock_key_slot_{} = 1", slot).unwrap();
+            if key_slot.read_lock {
+                writeln!(out, "rd_lock_key_slot_{} = 1", slot).unwrap();
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,218 @@
+use crate::Error;
+use aes::{Aes128, Aes192, Aes256};
+use byteorder::{LittleEndian, WriteBytesExt};
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher},
+    Ctr128BE,
+};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::fs::{create_dir_all, read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Value of `encrypt_type` in the boot header and `ef_sf_aes_mode` in eFuse
+pub fn aes_mode(key: &[u8]) -> u8 {
+    match key.len() {
+        16 => 1,
+        32 => 2,
+        _ => 3,
+    }
+}
+
+/// Parse a hex AES key, checking it is 128, 192 or 256 bits
+pub fn parse_aes_key(key: &str) -> Result<Vec<u8>, Error> {
+    let key = hex::decode(key.trim()).map_err(|_| Error::InvalidKey("AES key is not hex"))?;
+    if ![16, 24, 32].contains(&key.len()) {
+        return Err(Error::InvalidKey("AES key must be 128, 192 or 256 bits"));
+    }
+    Ok(key)
+}
+
+/// File recording the IVs already used, one `key-id iv` line each. IVs are
+/// checked against it as images are encrypted, but only recorded once the
+/// images are written to flash.
+#[derive(Debug)]
+pub struct IvLedger {
+    path: PathBuf,
+    /// Used but not yet recorded
+    pending: RefCell<Vec<String>>,
+}
+
+impl IvLedger {
+    pub fn new(path: PathBuf) -> Rc<Self> {
+        Rc::new(IvLedger {
+            path,
+            pending: RefCell::new(Vec::new()),
+        })
+    }
+    fn entry(key: &[u8], iv: &[u8]) -> String {
+        format!(
+            "{} {}",
+            hex::encode(&Sha256::digest(key)[..8]),
+            hex::encode(iv)
+        )
+    }
+    /// Refuse `iv` if it was used with `key` before
+    fn check(&self, key: &[u8], iv: &[u8]) -> Result<(), Error> {
+        let entry = Self::entry(key, iv);
+        let recorded = self.path.exists()
+            && read_to_string(&self.path)?
+                .lines()
+                .any(|line| line == entry);
+        if recorded || self.pending.borrow().contains(&entry) {
+            return Err(Error::IvReused(hex::encode(iv)));
+        }
+        self.pending.borrow_mut().push(entry);
+        Ok(())
+    }
+    /// Record the IVs used since the last call
+    pub fn record(&self) -> Result<(), Error> {
+        let pending = self.pending.replace(Vec::new());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        for entry in pending {
+            writeln!(file, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// AES key used to encrypt images for flash encryption.
+///
+/// Every image gets its own IV, the 96-bit nonce plus the number of images
+/// encrypted before it, so no two images share a keystream.
+#[derive(Debug, Clone)]
+pub struct EncryptKey {
+    key: Vec<u8>,
+    nonce: [u8; 12],
+    /// Images encrypted so far
+    images: u32,
+    ledger: Option<Rc<IvLedger>>,
+}
+
+impl EncryptKey {
+    /// Derive the IVs from `iv`
+    pub fn new(key: Vec<u8>, iv: &[u8]) -> Result<Self, Error> {
+        if iv.len() != 16 {
+            return Err(Error::InvalidKey("IV must be 128 bits"));
+        }
+        // The XIP decryptor adds the flash offset to the low word of the counter
+        if iv[12..] != [0; 4] {
+            return Err(Error::InvalidKey("the last 4 bytes of the IV must be 0"));
+        }
+        let mut nonce = [0; 12];
+        nonce.copy_from_slice(&iv[..12]);
+        Self::with_nonce(key, nonce)
+    }
+    /// Derive the IVs from a random nonce
+    pub fn random(key: Vec<u8>) -> Result<Self, Error> {
+        let mut nonce = [0; 12];
+        OsRng.fill_bytes(&mut nonce);
+        Self::with_nonce(key, nonce)
+    }
+    fn with_nonce(key: Vec<u8>, nonce: [u8; 12]) -> Result<Self, Error> {
+        if ![16, 24, 32].contains(&key.len()) {
+            return Err(Error::InvalidKey("AES key must be 128, 192 or 256 bits"));
+        }
+        Ok(EncryptKey {
+            key,
+            nonce,
+            images: 0,
+            ledger: None,
+        })
+    }
+    /// Refuse IVs recorded in `ledger` and hold the ones used for it
+    pub fn with_ledger(mut self, ledger: Rc<IvLedger>) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+    pub fn encrypt_type(&self) -> u8 {
+        aes_mode(&self.key)
+    }
+    /// Encrypt an image in place with AES-CTR under the next IV, the initial
+    /// 128-bit big-endian counter. Returns the IV block that follows the boot
+    /// header and signature: `iv (16) | crc32`.
+    pub fn encrypt(&mut self, data: &mut [u8]) -> Result<Vec<u8>, Error> {
+        let iv = self.next_iv()?;
+        match self.key.len() {
+            16 => Ctr128BE::<Aes128>::new(self.key[..].into(), (&iv).into()).apply_keystream(data),
+            24 => Ctr128BE::<Aes192>::new(self.key[..].into(), (&iv).into()).apply_keystream(data),
+            _ => Ctr128BE::<Aes256>::new(self.key[..].into(), (&iv).into()).apply_keystream(data),
+        }
+        let mut block = iv.to_vec();
+        let crc = crc::crc32::checksum_ieee(&block);
+        block.write_u32::<LittleEndian>(crc)?;
+        Ok(block)
+    }
+    fn next_iv(&mut self) -> Result<[u8; 16], Error> {
+        let mut counter = [0; 16];
+        counter[4..].copy_from_slice(&self.nonce);
+        let nonce = u128::from_be_bytes(counter).wrapping_add(self.images as u128);
+        // The XIP decryptor adds the flash offset to the last word
+        let mut iv = [0; 16];
+        iv[..12].copy_from_slice(&nonce.to_be_bytes()[4..]);
+        self.images += 1;
+        if let Some(ledger) = &self.ledger {
+            ledger.check(&self.key, &iv)?;
+        }
+        Ok(iv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_get_their_own_iv() {
+        let mut iv = [0; 16];
+        iv[11] = 0xff;
+        let mut key = EncryptKey::new(vec![0x11; 16], &iv).unwrap();
+        let first = key.encrypt(&mut [0; 32]).unwrap();
+        let second = key.encrypt(&mut [0; 32]).unwrap();
+        assert_eq!(first[..16], iv);
+        assert_eq!(second[10..16], [0x01, 0x00, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ledger_rejects_reused_iv() {
+        let path = std::env::temp_dir().join(format!("blflash-ivs-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let iv = [0x22; 12]
+            .iter()
+            .chain(&[0; 4])
+            .copied()
+            .collect::<Vec<_>>();
+        let encrypt = |ledger: Rc<IvLedger>| {
+            EncryptKey::new(vec![0x33; 16], &iv)
+                .unwrap()
+                .with_ledger(ledger)
+                .encrypt(&mut [0; 16])
+        };
+        // Building the same images again is fine until they are recorded
+        encrypt(IvLedger::new(path.clone())).unwrap();
+        let ledger = IvLedger::new(path.clone());
+        encrypt(ledger.clone()).unwrap();
+        assert!(!path.exists());
+        ledger.record().unwrap();
+        assert!(matches!(
+            encrypt(IvLedger::new(path.clone())),
+            Err(Error::IvReused(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
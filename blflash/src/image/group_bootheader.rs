@@ -0,0 +1,535 @@
+use super::{
+    bootheader::{default_boot2_offset, default_fw_offset},
+    BootHeader, BootHeaderCfgFile, EfuseCfg, EncryptKey, FlashCfg, SignKey,
+};
+use crate::Error;
+use byteorder::{NativeEndian, ReadBytesExt};
+use deku::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+/// Clock config of the BL808 boot header
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
+pub struct Bl808ClkCfg {
+    clkcfg_magic_code: u32,
+    xtal_type: u8,
+    mcu_clk: u8,
+    mcu_clk_div: u8,
+    mcu_bclk_div: u8,
+    mcu_pbclk_div: u8,
+    lp_div: u8,
+    dsp_clk: u8,
+    dsp_clk_div: u8,
+    dsp_bclk_div: u8,
+    dsp_pbclk: u8,
+    dsp_pbclk_div: u8,
+    emi_clk: u8,
+    emi_clk_div: u8,
+    flash_clk_type: u8,
+    flash_clk_div: u8,
+    wifipll_pu: u8,
+    aupll_pu: u8,
+    cpupll_pu: u8,
+    mipipll_pu: u8,
+    uhspll_pu: u8,
+    #[deku(update = "self.checksum()")]
+    clkcfg_crc32: u32,
+}
+
+/// Clock config of the BL616/BL618 boot header
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
+pub struct Bl616ClkCfg {
+    clkcfg_magic_code: u32,
+    xtal_type: u8,
+    mcu_clk: u8,
+    mcu_clk_div: u8,
+    mcu_bclk_div: u8,
+    mcu_pbclk_div: u8,
+    emi_clk: u8,
+    emi_clk_div: u8,
+    flash_clk_type: u8,
+    flash_clk_div: u8,
+    wifipll_pu: u8,
+    aupll_pu: u8,
+    #[serde(skip)]
+    _unused1: u8,
+    #[deku(update = "self.checksum()")]
+    clkcfg_crc32: u32,
+}
+
+/// Bitfields packed into the first word of `BasicCfg`, from the lowest bit
+pub const BASIC_CFG_BITFIELDS: &[(&str, u32)] = &[
+    ("sign", 2),
+    ("encrypt_type", 2),
+    ("key_sel", 2),
+    ("xts_mode", 1),
+    ("aes_region_lock", 1),
+    ("no_segment", 1),
+    ("boot2_enable", 1),
+    ("boot2_rollback", 1),
+    ("cpu_master_id", 4),
+    ("notload_in_bootrom", 1),
+    ("crc_ignore", 1),
+    ("hash_ignore", 1),
+    ("power_on_mm", 1),
+    ("em_sel", 3),
+    ("cmds_en", 1),
+    ("cmds_wrap_mode", 2),
+    ("cmds_wrap_len", 4),
+    ("icache_invalid", 1),
+    ("dcache_invalid", 1),
+    ("fpga_halt_release", 1),
+];
+
+/// Bitfields packed into the cache byte of `CpuCfg`, from the lowest bit
+pub const CPU_CFG_BITFIELDS: &[(&str, u32)] = &[
+    ("cache_enable", 1),
+    ("cache_wa", 1),
+    ("cache_wb", 1),
+    ("cache_wt", 1),
+    ("cache_way_dis", 4),
+];
+
+fn pack(bitfields: &[(&str, u32)], values: &[u8]) -> u32 {
+    bitfields
+        .iter()
+        .zip(values)
+        .rev()
+        .fold(0, |packed, ((_, bits), value)| {
+            (packed << bits) | (*value as u32 & ((1 << bits) - 1))
+        })
+}
+
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
+pub struct BasicCfg {
+    #[deku(skip)]
+    sign: u8,
+    #[deku(skip)]
+    encrypt_type: u8,
+    #[deku(skip)]
+    key_sel: u8,
+    #[deku(skip)]
+    xts_mode: u8,
+    #[deku(skip)]
+    aes_region_lock: u8,
+    #[deku(skip)]
+    no_segment: u8,
+    #[deku(skip)]
+    boot2_enable: u8,
+    #[deku(skip)]
+    boot2_rollback: u8,
+    #[deku(skip)]
+    cpu_master_id: u8,
+    #[deku(skip)]
+    notload_in_bootrom: u8,
+    #[deku(skip)]
+    crc_ignore: u8,
+    #[deku(skip)]
+    hash_ignore: u8,
+    #[deku(skip)]
+    power_on_mm: u8,
+    #[deku(skip)]
+    em_sel: u8,
+    #[deku(skip)]
+    cmds_en: u8,
+    #[deku(skip)]
+    cmds_wrap_mode: u8,
+    #[deku(skip)]
+    cmds_wrap_len: u8,
+    #[deku(skip)]
+    icache_invalid: u8,
+    #[deku(skip)]
+    dcache_invalid: u8,
+    #[deku(skip)]
+    fpga_halt_release: u8,
+    #[serde(skip)]
+    #[deku(update = "self.flags()")]
+    flags: u32,
+
+    /// Flash offset of the image from its header
+    group_image_offset: u32,
+    aes_region_len: u32,
+    img_len_cnt: u32,
+    hash_0: u32,
+    hash_1: u32,
+    hash_2: u32,
+    hash_3: u32,
+    hash_4: u32,
+    hash_5: u32,
+    hash_6: u32,
+    hash_7: u32,
+}
+
+/// Per-CPU config, the ROM starts every core whose config is enabled
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
+pub struct CpuCfg {
+    config_enable: u8,
+    halt_cpu: u8,
+    #[serde(skip)]
+    #[deku(update = "self.cache_flags()")]
+    cache_flags: u8,
+    #[serde(skip)]
+    _unused1: u8,
+    cache_range_h: u32,
+    cache_range_l: u32,
+    image_address_offset: u32,
+    boot_entry: u32,
+    msp_val: u32,
+
+    #[deku(skip)]
+    cache_enable: u8,
+    #[deku(skip)]
+    cache_wa: u8,
+    #[deku(skip)]
+    cache_wb: u8,
+    #[deku(skip)]
+    cache_wt: u8,
+    #[deku(skip)]
+    cache_way_dis: u8,
+}
+
+/// Boot header of the BL808 and BL616/BL618, which boot one image per group
+/// of CPUs. `C` is the chip's clock config.
+#[derive(Debug, Deserialize, Serialize, DekuWrite, Default, Clone)]
+pub struct GroupBootHeaderCfg<C: DekuWrite + DekuUpdate> {
+    magic_code: u32,
+    revision: u32,
+
+    #[serde(flatten)]
+    pub flash_cfg: FlashCfg,
+
+    #[serde(flatten)]
+    pub clk_cfg: C,
+
+    #[serde(flatten)]
+    pub basic_cfg: BasicCfg,
+
+    /// One config per core, in the order of `cores`
+    #[serde(skip)]
+    cpu_cfg: Vec<CpuCfg>,
+
+    boot2_pt_table_0: u32,
+    boot2_pt_table_1: u32,
+    flash_cfg_table_addr: u32,
+    flash_cfg_table_len: u32,
+    /// Patches and reserved words, sized by the chip
+    #[serde(skip)]
+    reserved: Vec<u8>,
+
+    #[deku(update = "self.checksum()")]
+    crc32: u32,
+
+    /// See `BootHeader::boot2_offset`
+    #[serde(default = "default_boot2_offset")]
+    #[deku(skip)]
+    boot2_offset: u32,
+    /// See `BootHeader::fw_offset`
+    #[serde(default = "default_fw_offset")]
+    #[deku(skip)]
+    fw_offset: u32,
+
+    /// Names of the cores, each has a `[BOOTHEADER_CFG.<core>]` table
+    #[serde(skip)]
+    #[deku(skip)]
+    cores: Vec<String>,
+}
+
+impl Bl808ClkCfg {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[4..data.len() - 4])
+    }
+}
+
+impl Bl616ClkCfg {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[4..data.len() - 4])
+    }
+}
+
+impl BasicCfg {
+    fn flags(&self) -> u32 {
+        pack(
+            BASIC_CFG_BITFIELDS,
+            &[
+                self.sign,
+                self.encrypt_type,
+                self.key_sel,
+                self.xts_mode,
+                self.aes_region_lock,
+                self.no_segment,
+                self.boot2_enable,
+                self.boot2_rollback,
+                self.cpu_master_id,
+                self.notload_in_bootrom,
+                self.crc_ignore,
+                self.hash_ignore,
+                self.power_on_mm,
+                self.em_sel,
+                self.cmds_en,
+                self.cmds_wrap_mode,
+                self.cmds_wrap_len,
+                self.icache_invalid,
+                self.dcache_invalid,
+                self.fpga_halt_release,
+            ],
+        )
+    }
+}
+
+impl CpuCfg {
+    fn cache_flags(&self) -> u8 {
+        pack(
+            CPU_CFG_BITFIELDS,
+            &[
+                self.cache_enable,
+                self.cache_wa,
+                self.cache_wb,
+                self.cache_wt,
+                self.cache_way_dis,
+            ],
+        ) as u8
+    }
+}
+
+impl<C: DekuWrite + DekuUpdate> GroupBootHeaderCfg<C> {
+    fn checksum(&self) -> u32 {
+        let data = self.to_bytes().unwrap();
+        crc::crc32::checksum_ieee(&data[0..data.len() - 4])
+    }
+}
+
+impl<C> GroupBootHeaderCfg<C>
+where
+    C: DekuWrite + DekuUpdate + Serialize + serde::de::DeserializeOwned + Clone,
+{
+    /// Parse `efuse_bootheader_cfg.conf` with overrides, the CPU configs are
+    /// read from the `[BOOTHEADER_CFG.<core>]` tables
+    pub fn from_toml_with_overrides(
+        data: &[u8],
+        overrides: &[(String, i64)],
+        cores: &[String],
+        reserved_len: usize,
+    ) -> Result<Self, Error> {
+        let bitfields = [BASIC_CFG_BITFIELDS, CPU_CFG_BITFIELDS].concat();
+        let cfg = BootHeaderCfgFile::<Self>::apply_overrides(data, overrides, &bitfields)?;
+        let cfg = &cfg["BOOTHEADER_CFG"];
+
+        let mut header: Self = cfg.clone().try_into()?;
+        for core in cores {
+            let cpu_cfg = cfg
+                .get(core)
+                .cloned()
+                .ok_or_else(|| Error::UnknownBootHeaderField(core.to_string()))?;
+            header.cpu_cfg.push(cpu_cfg.try_into()?);
+        }
+        header.reserved = vec![0; reserved_len];
+        header.cores = cores.to_vec();
+        Ok(header)
+    }
+    fn update_sha256(&mut self, hash: &[u8]) -> Result<(), Error> {
+        let mut reader = Cursor::new(hash);
+        self.basic_cfg.hash_0 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_1 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_2 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_3 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_4 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_5 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_6 = reader.read_u32::<NativeEndian>()?;
+        self.basic_cfg.hash_7 = reader.read_u32::<NativeEndian>()?;
+        Ok(())
+    }
+}
+
+impl<C> BootHeader for GroupBootHeaderCfg<C>
+where
+    C: DekuWrite
+        + DekuContainerWrite
+        + DekuUpdate
+        + Serialize
+        + serde::de::DeserializeOwned
+        + Clone,
+{
+    fn boot2_offset(&self) -> u32 {
+        self.boot2_offset
+    }
+    fn fw_offset(&self) -> u32 {
+        self.fw_offset
+    }
+    fn sector_size(&self) -> u32 {
+        self.flash_cfg.sector_size()
+    }
+    fn set_sign_key(&mut self, _key: SignKey) -> Result<(), Error> {
+        Err(Error::SecurityNotSupported)
+    }
+    fn set_encrypt_key(&mut self, _key: EncryptKey) -> Result<(), Error> {
+        Err(Error::SecurityNotSupported)
+    }
+    fn efuse_cfg(&self) -> EfuseCfg {
+        EfuseCfg::default()
+    }
+    fn iv_offset(&self, _signed: bool) -> Result<usize, Error> {
+        Err(Error::SecurityNotSupported)
+    }
+    fn select_core(&mut self, core: &str) -> Result<(), Error> {
+        let index = self
+            .cores
+            .iter()
+            .position(|name| *name == core)
+            .ok_or_else(|| Error::UnknownCore(core.to_string()))?;
+        for (i, cpu_cfg) in self.cpu_cfg.iter_mut().enumerate() {
+            cpu_cfg.config_enable = (i == index) as u8;
+        }
+        Ok(())
+    }
+    fn make_image(&mut self, offset: usize, mut image: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let binlen = image.len().div_ceil(16) * 16;
+        image.resize(binlen, 0xFF);
+        let hash = Sha256::digest(&image);
+        self.update_sha256(&hash[..])?;
+        self.basic_cfg.img_len_cnt = image.len() as u32;
+        self.basic_cfg.group_image_offset = offset as u32;
+        self.flash_cfg.update()?;
+        self.clk_cfg.update()?;
+        self.basic_cfg.update()?;
+        for cpu_cfg in &mut self.cpu_cfg {
+            cpu_cfg.update()?;
+        }
+        self.update()?;
+
+        let mut header = self.to_bytes()?;
+        if header.len() > offset {
+            return Err(Error::ImageOffsetTooSmall(offset, header.len()));
+        }
+
+        header.resize(offset, 0xff);
+        header.append(&mut image);
+
+        Ok(header)
+    }
+    fn strip_header<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], Error> {
+        // group_image_offset follows the flash and clock configs and the flag word
+        let at = 8 + self.flash_cfg.to_bytes()?.len() + self.clk_cfg.to_bytes()?.len() + 4;
+        let offset = image
+            .get(at..)
+            .and_then(|mut field| field.read_u32::<NativeEndian>().ok())
+            .ok_or(Error::InvalidBootHeader)?;
+        image
+            .get(offset as usize..)
+            .filter(|_| offset as usize > at)
+            .ok_or(Error::InvalidBootHeader)
+    }
+    fn to_toml(&self) -> Result<String, Error> {
+        let mut cfg =
+            toml::Value::try_from(self).map_err(|e| Error::TomlSerialize(e.to_string()))?;
+        if let Some(table) = cfg.as_table_mut() {
+            for (core, cpu_cfg) in self.cores.iter().zip(&self.cpu_cfg) {
+                let cpu_cfg = toml::Value::try_from(cpu_cfg)
+                    .map_err(|e| Error::TomlSerialize(e.to_string()))?;
+                table.insert(core.to_string(), cpu_cfg);
+            }
+        }
+        // Going through `toml::Value` puts the CPU tables after the plain fields
+        BootHeaderCfgFile {
+            boot_header_cfg: cfg,
+        }
+        .to_toml()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::ChipType;
+    use byteorder::LittleEndian;
+
+    /// Header `chip_type` makes for a 100 byte image at 0x1000, with the
+    /// given basic config bitfields set and the others cleared
+    fn header(chip_type: ChipType, bitfields: &[(&str, i64)]) -> Vec<u8> {
+        let chip = chip_type.chip();
+        let overrides = BASIC_CFG_BITFIELDS
+            .iter()
+            .map(|(name, _)| {
+                let value = bitfields
+                    .iter()
+                    .find(|(field, _)| field == name)
+                    .map_or(0, |(_, value)| *value);
+                (name.to_string(), value)
+            })
+            .collect::<Vec<_>>();
+        let mut boot_header_cfg = chip
+            .read_boot_header(chip.default_boot_header_cfg(), &overrides)
+            .unwrap();
+        let image = boot_header_cfg.make_image(0x1000, vec![0x5a; 100]).unwrap();
+        assert!(image[chip.boot_header_len()..0x1000]
+            .iter()
+            .all(|byte| *byte == 0xff));
+        image[..chip.boot_header_len()].to_vec()
+    }
+
+    /// Check `header` against the documented layout: magic and revision,
+    /// the 92 byte flash config, the clock config, the basic config, then
+    /// the CPU configs, patches and reserved words up to the CRC
+    fn check_layout(header: &[u8], len: usize, clk_len: usize, flags: u32) {
+        let word = |at: usize| (&header[at..]).read_u32::<LittleEndian>().unwrap();
+        assert_eq!(header.len(), len);
+        assert_eq!(&header[..4], b"BFNP");
+        assert_eq!(&header[8..12], b"FCFG");
+        assert_eq!(
+            word(96),
+            crc::crc32::checksum_ieee(&header[12..96]),
+            "flash config crc"
+        );
+
+        let clk = 100;
+        assert_eq!(&header[clk..clk + 4], b"PCFG");
+        assert_eq!(
+            word(clk + clk_len - 4),
+            crc::crc32::checksum_ieee(&header[clk + 4..clk + clk_len - 4]),
+            "clock config crc"
+        );
+
+        let basic = clk + clk_len;
+        assert_eq!(word(basic), flags, "basic config flags");
+        assert_eq!(word(basic + 4), 0x1000, "group_image_offset");
+        assert_eq!(word(basic + 12), 112, "img_len_cnt");
+        let mut image = vec![0x5a; 100];
+        image.resize(112, 0xff);
+        assert_eq!(header[basic + 16..basic + 48], Sha256::digest(&image)[..]);
+
+        assert_eq!(
+            word(len - 4),
+            crc::crc32::checksum_ieee(&header[..len - 4]),
+            "header crc"
+        );
+    }
+
+    /// cpu_master_id is bits 11-14, crc_ignore bit 16, em_sel bits 19-21,
+    /// cmds_wrap_len bits 25-28 and fpga_halt_release bit 31
+    const BITFIELDS: &[(&str, i64)] = &[
+        ("cpu_master_id", 2),
+        ("crc_ignore", 1),
+        ("em_sel", 5),
+        ("cmds_wrap_len", 9),
+        ("fpga_halt_release", 1),
+    ];
+    const FLAGS: u32 = 2 << 11 | 1 << 16 | 5 << 19 | 9 << 25 | 1 << 31;
+
+    #[test]
+    fn basic_cfg_bitfields_fill_one_word() {
+        let bits: u32 = BASIC_CFG_BITFIELDS.iter().map(|(_, bits)| bits).sum();
+        assert_eq!(bits, 32);
+    }
+
+    #[test]
+    fn bl808_header_layout() {
+        check_layout(&header(ChipType::Bl808, BITFIELDS), 352, 28, FLAGS);
+    }
+
+    #[test]
+    fn bl616_header_layout() {
+        check_layout(&header(ChipType::Bl616, BITFIELDS), 256, 20, FLAGS);
+    }
+}
@@ -1,5 +1,25 @@
 mod bootheader;
+mod efuse;
+mod encrypt;
+mod group_bootheader;
+mod ota;
 mod partition;
+mod sign;
+mod xz;
 
-pub use bootheader::{BootHeaderCfg, BootHeaderCfgFile};
-pub use partition::PartitionCfg;
+pub use bootheader::{
+    has_boot_header, BootHeader, BootHeaderCfg, BootHeaderCfgFile, FlashCfg, BITFIELDS,
+    BOOT_HEADER_MAGIC,
+};
+pub use efuse::{EfuseCfg, KeySlot};
+pub use encrypt::{aes_mode, parse_aes_key, EncryptKey, IvLedger};
+pub use group_bootheader::{
+    BasicCfg, Bl616ClkCfg, Bl808ClkCfg, CpuCfg, GroupBootHeaderCfg, BASIC_CFG_BITFIELDS,
+    CPU_CFG_BITFIELDS,
+};
+pub use ota::{make_ota, OtaHeader, OTA_HEADER_LEN};
+pub use partition::{
+    Entry, PartitionCfg, Table, FW_TYPE, PARTITION_TABLE_MAGIC, PARTITION_TABLE_SIZE,
+};
+pub use sign::{public_key_hash, read_public_key, SignKey, SIGNATURE_BLOCKS_LEN};
+pub use xz::compress_xz;
@@ -0,0 +1,63 @@
+use super::compress_xz;
+use crate::Error;
+use deku::prelude::*;
+use sha2::{Digest, Sha256};
+
+pub const OTA_HEADER_LEN: usize = 512;
+
+#[derive(Debug, DekuWrite, Default)]
+#[deku(magic = b"BL60X_OTA_Ver1.0")]
+pub struct OtaHeader {
+    /// `RAW` or `XZ`
+    pub r#type: [u8; 4],
+    /// payload length
+    pub len: u32,
+    _pad0: [u8; 8],
+    pub ver_hardware: [u8; 16],
+    pub ver_software: [u8; 16],
+    /// sha256 of the payload
+    pub sha256: [u8; 32],
+}
+
+impl OtaHeader {
+    fn version(version: &str) -> Result<[u8; 16], Error> {
+        let mut field = [0u8; 16];
+        if version.len() >= field.len() {
+            return Err(Error::VersionTooLong(version.to_string()));
+        }
+        field[..version.len()].copy_from_slice(version.as_bytes());
+        Ok(field)
+    }
+}
+
+/// Build an OTA package from the contents of the FW partition, which is the
+/// firmware with its boot header.
+pub fn make_ota(
+    fw_image: &[u8],
+    compress: bool,
+    ver_hardware: &str,
+    ver_software: &str,
+) -> Result<Vec<u8>, Error> {
+    let (r#type, mut payload) = if compress {
+        (*b"XZ\0\0", compress_xz(fw_image)?)
+    } else {
+        (*b"RAW\0", fw_image.to_vec())
+    };
+
+    let mut sha256 = [0u8; 32];
+    sha256.copy_from_slice(&Sha256::digest(&payload));
+    let header = OtaHeader {
+        r#type,
+        len: payload.len() as u32,
+        ver_hardware: OtaHeader::version(ver_hardware)?,
+        ver_software: OtaHeader::version(ver_software)?,
+        sha256,
+        ..Default::default()
+    };
+
+    let mut ota = header.to_bytes()?;
+    ota.resize(OTA_HEADER_LEN, 0);
+    ota.append(&mut payload);
+
+    Ok(ota)
+}
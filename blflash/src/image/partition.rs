@@ -1,48 +1,73 @@
-use deku::prelude::*;
+use crate::{Error, PartitionError};
 use bitvec::prelude::*;
+use deku::prelude::*;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
 use std::io::Write;
 use std::iter;
+use std::ops::Range;
+
+/// Every partition table starts with this magic
+pub const PARTITION_TABLE_MAGIC: &[u8] = b"BFPT";
+/// Space reserved for each copy of the partition table
+pub const PARTITION_TABLE_SIZE: u32 = 0x1000;
+pub const PARTITION_HEADER_LEN: usize = 16;
+pub const PARTITION_ENTRY_LEN: usize = 36;
+/// Partitions must start and end on a flash sector
+pub const PARTITION_ALIGN: u32 = 0x1000;
+/// Type of the firmware entry
+pub const FW_TYPE: u8 = 0;
+/// Entries boot2 can't work without, by type
+const MANDATORY_ENTRIES: &[(u8, &str)] = &[(FW_TYPE, "FW")];
 
-#[derive(Debug, Deserialize, DekuWrite, Default)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 #[deku(magic = b"\x42\x46\x50\x54\x00\x00")]
 pub struct PartitionCfg {
     #[serde(skip)]
     #[deku(update = "self.pt_entry.len()")]
-    pub entry_len: u32,
+    pub entry_len: u16,
+    /// Boot2 uses the valid table copy with the greatest age
     #[serde(skip)]
-    #[deku(update = "0")]
-    _unused1: u16,
+    pub age: u32,
     #[serde(skip)]
     #[deku(update = "self.header_checksum()")]
     pub checksum: u32,
     #[deku(skip)]
     pub pt_table: Table,
+    #[deku(count = "entry_len")]
     pub pt_entry: Vec<Entry>,
     #[serde(skip)]
     #[deku(update = "self.checksum()")]
     pub file_checksum: u32,
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 pub struct Table {
     pub address0: u32,
     pub address1: u32,
 }
 
-#[derive(Debug, Deserialize, DekuWrite, Default)]
+#[derive(Debug, Deserialize, DekuRead, DekuWrite, Default, Clone)]
 pub struct Entry {
-    #[deku(bytes = "3")]
-    pub r#type: u32,
-    #[deku(writer = "Entry::write_name(name, deku::output)")]
+    pub r#type: u8,
+    #[serde(default)]
+    pub device: u8,
+    /// Slot boot2 loads the entry from
+    #[serde(default)]
+    pub active_index: u8,
+    #[deku(
+        map = "Entry::read_name",
+        writer = "Entry::write_name(name, deku::output)"
+    )]
     pub name: String,
     pub address0: u32,
     pub address1: u32,
     pub size0: u32,
     pub size1: u32,
     pub len: u32,
-    #[serde(skip)]
-    pub _unused1: u32,
+    #[serde(default)]
+    pub age: u32,
 }
 
 impl PartitionCfg {
@@ -52,11 +77,183 @@ impl PartitionCfg {
     }
     fn checksum(&self) -> u32 {
         let data = self.to_bytes().unwrap();
-        crc::crc32::checksum_ieee(&data[16..16 + 36 * self.pt_entry.len()])
+        crc::crc32::checksum_ieee(
+            &data[PARTITION_HEADER_LEN
+                ..PARTITION_HEADER_LEN + PARTITION_ENTRY_LEN * self.pt_entry.len()],
+        )
+    }
+    /// Parse a partition table as it is stored in flash, checking both the
+    /// header and the entries checksum.
+    pub fn from_flash(data: &[u8]) -> Result<Self, Error> {
+        let (_, cfg) = PartitionCfg::from_bytes((data, 0))?;
+
+        let header_checksum = crc::crc32::checksum_ieee(&data[0..12]);
+        if cfg.checksum != header_checksum {
+            return Err(Error::PartitionChecksum(cfg.checksum, header_checksum));
+        }
+        let entries_end = PARTITION_HEADER_LEN + PARTITION_ENTRY_LEN * cfg.pt_entry.len();
+        let file_checksum = crc::crc32::checksum_ieee(&data[PARTITION_HEADER_LEN..entries_end]);
+        if cfg.file_checksum != file_checksum {
+            return Err(Error::PartitionChecksum(cfg.file_checksum, file_checksum));
+        }
+
+        Ok(cfg)
+    }
+    /// Index of the table copy boot2 will use: the valid one with the
+    /// greatest age, the first one on a tie.
+    pub fn active_table(tables: &[Option<PartitionCfg>]) -> Option<usize> {
+        tables
+            .iter()
+            .enumerate()
+            .filter_map(|(index, table)| table.as_ref().map(|table| (index, table.age)))
+            .fold(None, |active, (index, age)| match active {
+                Some((_, active_age)) if active_age >= age => active,
+                _ => Some((index, age)),
+            })
+            .map(|(index, _)| index)
+    }
+    pub fn find_entry(&self, name: &str) -> Option<&Entry> {
+        self.pt_entry.iter().find(|entry| entry.name == name)
+    }
+    pub fn find_entry_mut(&mut self, name: &str) -> Option<&mut Entry> {
+        self.pt_entry.iter_mut().find(|entry| entry.name == name)
+    }
+    pub fn find_type(&self, r#type: u8) -> Option<&Entry> {
+        self.pt_entry.iter().find(|entry| entry.r#type == r#type)
+    }
+    pub fn find_type_mut(&mut self, r#type: u8) -> Option<&mut Entry> {
+        self.pt_entry
+            .iter_mut()
+            .find(|entry| entry.r#type == r#type)
+    }
+    /// Check the table for layout errors before it is written to a flash of
+    /// `flash_size` bytes, reporting every problem found.
+    pub fn validate(&self, flash_size: u32) -> Result<(), Error> {
+        let mut errors = Vec::new();
+        let Table { address0, address1 } = self.pt_table;
+
+        let mut regions = vec![("boot2".to_string(), 0..address0.min(address1))];
+        for (name, address) in &[
+            ("partition table 0", address0),
+            ("partition table 1", address1),
+        ] {
+            match address.checked_add(PARTITION_TABLE_SIZE) {
+                Some(end) => regions.push((name.to_string(), *address..end)),
+                None => errors.push(PartitionError::AddressOverflow(format!(
+                    "{} ({:#x})",
+                    name, address
+                ))),
+            }
+        }
+        let mut types = HashMap::new();
+
+        for entry in &self.pt_entry {
+            if entry.name.len() > 8 {
+                errors.push(PartitionError::NameTooLong(entry.name.clone()));
+            }
+            if let Some(other) = types.insert(entry.r#type, &entry.name) {
+                errors.push(PartitionError::DuplicateType(
+                    entry.r#type,
+                    other.clone(),
+                    entry.name.clone(),
+                ));
+            }
+            for (slot, range) in entry.slots() {
+                let region = format!(
+                    "{:?} slot {} ({:#x}..{:#x})",
+                    entry.name, slot, range.start, range.end
+                );
+                if range.start % PARTITION_ALIGN != 0 || range.end % PARTITION_ALIGN != 0 {
+                    errors.push(PartitionError::Unaligned(region.clone()));
+                }
+                if range.end > flash_size {
+                    errors.push(PartitionError::OutOfFlash(region.clone(), flash_size));
+                }
+                for (other, other_range) in &regions {
+                    if range.start < other_range.end && other_range.start < range.end {
+                        errors.push(PartitionError::Overlap(region.clone(), other.clone()));
+                    }
+                }
+                regions.push((region, range));
+            }
+            if entry.slot(entry.active_index as usize).is_none() {
+                errors.push(PartitionError::UnusedActiveSlot(
+                    entry.name.clone(),
+                    entry.active_index,
+                ));
+            }
+        }
+
+        for (r#type, name) in MANDATORY_ENTRIES {
+            if !types.contains_key(r#type) {
+                errors.push(PartitionError::MissingEntry(name));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidPartitionTable(errors))
+        }
+    }
+    /// Serialize the table as it is stored in flash, updating the checksums
+    pub fn to_flash(&mut self) -> Result<Vec<u8>, Error> {
+        self.update()?;
+        Ok(self.to_bytes()?)
+    }
+    /// Render the table in the same format as `partition_cfg.toml`
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "[pt_table]").unwrap();
+        writeln!(out, "#partition table is 4K in size").unwrap();
+        writeln!(out, "address0 = 0x{:X}", self.pt_table.address0).unwrap();
+        writeln!(out, "address1 = 0x{:X}", self.pt_table.address1).unwrap();
+        for entry in &self.pt_entry {
+            writeln!(out).unwrap();
+            writeln!(out, "[[pt_entry]]").unwrap();
+            writeln!(out, "type = {}", entry.r#type).unwrap();
+            writeln!(out, "name = {}", toml::Value::from(entry.name.as_str())).unwrap();
+            writeln!(out, "device = {}", entry.device).unwrap();
+            writeln!(out, "address0 = 0x{:X}", entry.address0).unwrap();
+            writeln!(out, "size0 = 0x{:X}", entry.size0).unwrap();
+            writeln!(out, "address1 = 0x{:X}", entry.address1).unwrap();
+            writeln!(out, "size1 = 0x{:X}", entry.size1).unwrap();
+            writeln!(
+                out,
+                "# compressed image must set len,normal image can left it to 0"
+            )
+            .unwrap();
+            writeln!(out, "len = {}", entry.len).unwrap();
+            if entry.active_index != 0 {
+                writeln!(out, "active_index = {}", entry.active_index).unwrap();
+            }
+            if entry.age != 0 {
+                writeln!(out, "age = {}", entry.age).unwrap();
+            }
+        }
+        out
     }
 }
 
 impl Entry {
+    /// Flash range of the given slot, if it is used
+    pub fn slot(&self, slot: usize) -> Option<Range<u32>> {
+        self.slots()
+            .find(|(index, _)| *index == slot)
+            .map(|(_, range)| range)
+    }
+    /// Flash ranges of the used slots, by slot index
+    pub fn slots(&self) -> impl Iterator<Item = (usize, Range<u32>)> {
+        vec![(self.address0, self.size0), (self.address1, self.size1)]
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (_, size))| *size > 0)
+            .map(|(slot, (address, size))| (slot, address..address.saturating_add(size)))
+    }
+    fn read_name(bytes: [u8; 9]) -> Result<String, DekuError> {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
     fn write_name(name: &str, output: &mut BitVec<u8, Msb0>) -> Result<(), DekuError> {
         if name.len() > 8 {
             return Err(DekuError::Unexpected("name too long".to_string()));
@@ -70,3 +267,173 @@ impl Entry {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::bl602;
+
+    fn template() -> PartitionCfg {
+        toml::from_slice(bl602::DEFAULT_PARTITION_CFG).unwrap()
+    }
+
+    #[test]
+    fn flash_round_trip() {
+        let mut cfg = template();
+        cfg.age = 3;
+        cfg.pt_entry[0].active_index = 1;
+        let data = cfg.to_flash().unwrap();
+        assert!(data.starts_with(PARTITION_TABLE_MAGIC));
+
+        let mut read = PartitionCfg::from_flash(&data).unwrap();
+        assert_eq!(read.age, 3);
+        assert_eq!(read.pt_entry.len(), cfg.pt_entry.len());
+        assert_eq!(read.pt_entry[0].active_index, 1);
+        assert_eq!(read.to_flash().unwrap(), data);
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let mut cfg = template();
+        cfg.pt_entry[0].age = 2;
+        let mut read: PartitionCfg = toml::from_str(&cfg.to_toml()).unwrap();
+        assert_eq!(read.pt_entry[0].age, 2);
+        assert_eq!(read.to_flash().unwrap(), cfg.to_flash().unwrap());
+    }
+
+    #[test]
+    fn from_flash_rejects_bad_checksums_and_magic() {
+        let data = template().to_flash().unwrap();
+
+        let mut bad_header = data.clone();
+        bad_header[8] ^= 1;
+        assert!(matches!(
+            PartitionCfg::from_flash(&bad_header),
+            Err(Error::PartitionChecksum(..))
+        ));
+
+        let mut bad_entries = data.clone();
+        bad_entries[PARTITION_HEADER_LEN + 12] ^= 1;
+        assert!(matches!(
+            PartitionCfg::from_flash(&bad_entries),
+            Err(Error::PartitionChecksum(..))
+        ));
+
+        let mut bad_magic = data;
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            PartitionCfg::from_flash(&bad_magic),
+            Err(Error::ParseError(_))
+        ));
+        assert!(PartitionCfg::from_flash(&[0xff; 16]).is_err());
+    }
+
+    /// Errors `validate` finds in the template with `change` applied
+    fn errors(change: impl FnOnce(&mut PartitionCfg)) -> Vec<PartitionError> {
+        let mut cfg = template();
+        change(&mut cfg);
+        match cfg.validate(0x200000) {
+            Ok(()) => Vec::new(),
+            Err(Error::InvalidPartitionTable(errors)) => errors,
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    fn entry(cfg: &mut PartitionCfg, name: &str) -> usize {
+        cfg.pt_entry
+            .iter()
+            .position(|entry| entry.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn template_is_valid() {
+        assert!(errors(|_| {}).is_empty());
+    }
+
+    #[test]
+    fn reports_unaligned_partitions() {
+        let errors = errors(|cfg| {
+            let fw = entry(cfg, "FW");
+            cfg.pt_entry[fw].size1 -= 0x10;
+        });
+        assert!(matches!(errors.as_slice(), [PartitionError::Unaligned(_)]));
+    }
+
+    #[test]
+    fn reports_overlaps() {
+        let errors = errors(|cfg| {
+            let fw = entry(cfg, "FW");
+            cfg.pt_entry[fw].address0 = cfg.pt_table.address1;
+        });
+        assert!(errors
+            .iter()
+            .any(|error| matches!(error, PartitionError::Overlap(_, table) if table == "partition table 1")));
+    }
+
+    #[test]
+    fn reports_partitions_out_of_flash() {
+        let errors = errors(|cfg| {
+            let fw = entry(cfg, "FW");
+            cfg.pt_entry[fw].address1 = 0x200000;
+        });
+        assert!(matches!(
+            errors.as_slice(),
+            [PartitionError::OutOfFlash(_, 0x200000)]
+        ));
+    }
+
+    #[test]
+    fn reports_table_address_overflow() {
+        let errors = errors(|cfg| cfg.pt_table.address1 = 0xFFFFF800);
+        assert!(errors
+            .iter()
+            .any(|error| matches!(error, PartitionError::AddressOverflow(_))));
+    }
+
+    #[test]
+    fn reports_long_names() {
+        let errors = errors(|cfg| cfg.pt_entry[0].name = "TOOLONGNAME".to_string());
+        assert!(matches!(
+            errors.as_slice(),
+            [PartitionError::NameTooLong(_)]
+        ));
+    }
+
+    #[test]
+    fn reports_duplicate_types() {
+        let errors = errors(|cfg| {
+            let r#type = cfg.pt_entry[0].r#type;
+            cfg.pt_entry[1].r#type = r#type;
+        });
+        assert!(matches!(
+            errors.as_slice(),
+            [PartitionError::DuplicateType(..)]
+        ));
+    }
+
+    #[test]
+    fn reports_missing_firmware() {
+        let errors = errors(|cfg| {
+            let fw = entry(cfg, "FW");
+            cfg.pt_entry.remove(fw);
+        });
+        assert!(matches!(
+            errors.as_slice(),
+            [PartitionError::MissingEntry("FW")]
+        ));
+    }
+
+    #[test]
+    fn reports_unused_active_slot() {
+        let errors = errors(|cfg| {
+            let fw = entry(cfg, "FW");
+            cfg.pt_entry[fw].size1 = 0;
+            cfg.pt_entry[fw].active_index = 1;
+        });
+        assert!(matches!(
+            errors.as_slice(),
+            [PartitionError::UnusedActiveSlot(_, 1)]
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::Error;
+use byteorder::{LittleEndian, WriteBytesExt};
+use p256::{
+    ecdsa::{signature::Signer, Signature, SigningKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    PublicKey, SecretKey,
+};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Length of the public key and signature blocks behind a signed header
+pub const SIGNATURE_BLOCKS_LEN: usize = 64 + 4 + 4 + 64 + 4;
+
+/// Uncompressed public key without the leading 0x04, as the ROM expects
+fn raw_public_key(public_key: &PublicKey) -> Vec<u8> {
+    public_key.to_encoded_point(false).as_bytes()[1..].to_vec()
+}
+
+/// Read the raw public key from a public or private key PEM
+pub fn read_public_key(pem: &str) -> Result<Vec<u8>, Error> {
+    match PublicKey::from_public_key_pem(pem) {
+        Ok(public_key) => Ok(raw_public_key(&public_key)),
+        Err(_) => Ok(SignKey::from_pem(pem)?.public_key()),
+    }
+}
+
+/// Hash of the raw public key, as written to eFuse for secure boot
+pub fn public_key_hash(public_key: &[u8]) -> Vec<u8> {
+    Sha256::digest(public_key).to_vec()
+}
+
+/// ECDSA P-256 key used to sign images for secure boot
+#[derive(Debug, Clone)]
+pub struct SignKey(SigningKey);
+
+impl SignKey {
+    /// Load a private key in SEC1 (`EC PRIVATE KEY`) or PKCS#8 PEM format
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let secret = SecretKey::from_sec1_pem(pem)
+            .or_else(|_| SecretKey::from_pkcs8_pem(pem))
+            .map_err(|_| Error::InvalidKey("expected a P-256 private key in PEM format"))?;
+        Ok(SignKey(secret.into()))
+    }
+    /// Generate a new random key
+    pub fn generate() -> Self {
+        SignKey(SigningKey::random(&mut OsRng))
+    }
+    /// Private key in PKCS#8 PEM format
+    pub fn to_pem(&self) -> Result<String, Error> {
+        let pem = self
+            .0
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|_| Error::InvalidKey("failed to encode the private key"))?;
+        Ok(pem.to_string())
+    }
+    /// Public key in PEM format
+    pub fn public_key_pem(&self) -> Result<String, Error> {
+        PublicKey::from(self.0.verifying_key())
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|_| Error::InvalidKey("failed to encode the public key"))
+    }
+    pub fn public_key(&self) -> Vec<u8> {
+        raw_public_key(&PublicKey::from(self.0.verifying_key()))
+    }
+    /// Public key and signature blocks that follow the boot header.
+    ///
+    /// The ROM verifies the signature against SHA-256 of the image hash:
+    /// `pk (64) | crc32 | sig_len (4) | sig (64) | crc32`.
+    pub fn signature_blocks(&self, hash: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature: Signature = self.0.sign(hash);
+        let signature = signature.to_bytes();
+
+        let mut blocks = self.public_key();
+        let pk_crc = crc::crc32::checksum_ieee(&blocks);
+        blocks.write_u32::<LittleEndian>(pk_crc)?;
+
+        let mut sig_block = Vec::new();
+        sig_block.write_u32::<LittleEndian>(signature.len() as u32)?;
+        sig_block.extend_from_slice(&signature);
+        let sig_crc = crc::crc32::checksum_ieee(&sig_block);
+        sig_block.write_u32::<LittleEndian>(sig_crc)?;
+
+        blocks.append(&mut sig_block);
+        Ok(blocks)
+    }
+}
@@ -0,0 +1,22 @@
+use crate::Error;
+use std::io::Write;
+use xz2::{
+    stream::{Check, Filters, LzmaOptions, Stream},
+    write::XzEncoder,
+};
+
+/// Boot2 decompresses with a 32 KiB window and only checks CRC32
+const DICT_SIZE: u32 = 32 * 1024;
+
+/// Compress data into the xz format boot2 and the OTA handler can decode
+pub fn compress_xz(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut options = LzmaOptions::new_preset(9)?;
+    options.dict_size(DICT_SIZE);
+    let mut filters = Filters::new();
+    filters.lzma2(&options);
+    let stream = Stream::new_stream_encoder(&filters, Check::Crc32)?;
+
+    let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
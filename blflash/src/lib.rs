@@ -1,26 +1,39 @@
 pub mod chip;
 mod connection;
+pub mod dtb;
 pub mod elf;
 mod error;
 mod flasher;
 pub mod image;
+pub mod records;
 
-pub use error::{Error, RomError};
+pub use error::{Error, PartitionError, RomError};
 pub use flasher::Flasher;
 
 use crate::{
-    chip::{
-        bl602::{self, Bl602},
-        Chip,
-    },
+    chip::{Chip, ChipType, FlashImages},
+    dtb::DeviceTree,
     elf::{FirmwareImage, RomSegment},
-    image::BootHeaderCfgFile,
+    image::{
+        has_boot_header, make_ota, parse_aes_key, public_key_hash, read_public_key, BootHeader,
+        EfuseCfg, EncryptKey, IvLedger, PartitionCfg, SignKey, FW_TYPE, PARTITION_TABLE_MAGIC,
+        PARTITION_TABLE_SIZE,
+    },
+    records::{RecordFormat, RecordImage},
 };
+use byteorder::{LittleEndian, ReadBytesExt};
+use rand_core::{OsRng, RngCore};
 use serial::{BaudRate, CharSize, FlowControl, Parity, SerialPort, SerialPortSettings, StopBits};
+use sha2::{Digest, Sha256};
 use std::{
     borrow::Cow,
-    fs::{read, File},
-    path::PathBuf,
+    fmt::{self, Write as _},
+    fs::{create_dir_all, read, write, File, OpenOptions},
+    io::Write as _,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
 };
 use structopt::StructOpt;
 
@@ -29,40 +42,108 @@ pub struct Connection {
     /// Serial port
     #[structopt(short, long)]
     pub port: String,
+    /// Chip family: bl602, bl702, bl808 or bl616, detected from the boot ROM if not given
+    #[structopt(long)]
+    pub chip: Option<ChipType>,
     /// Flash baud rate
     #[structopt(short, long, default_value = "1000000")]
     pub baud_rate: usize,
     /// Initial baud rate
     #[structopt(long, default_value = "115200")]
     pub initial_baud_rate: usize,
+    /// Path to a custom eflash_loader build, instead of the bundled one.
+    /// The bundled BL602 build is for a 40 MHz crystal, other boards need
+    /// the SDK's build for theirs.
+    #[structopt(long, parse(from_os_str))]
+    pub eflash_loader: Option<PathBuf>,
 }
 
 #[derive(StructOpt)]
 pub struct Boot2Opt {
-    /// Path to partition_cfg.toml, default to be partition/partition_cfg_2M.toml
+    /// Path to partition_cfg.toml, default to the chip's bundled one for the flash size
     #[structopt(long, parse(from_os_str))]
     pub partition_cfg: Option<PathBuf>,
-    /// Path to efuse_bootheader_cfg.conf
+    #[structopt(flatten)]
+    pub boot_header: BootHeaderOpt,
+    /// Path to a custom boot2 build, instead of the bundled one
     #[structopt(long, parse(from_os_str))]
-    pub boot_header_cfg: Option<PathBuf>,
-    /// Path to ro_params.dtb
+    pub boot2: Option<PathBuf>,
+    /// Path to ro_params.dtb, or a .dts source to compile
     #[structopt(long, parse(from_os_str))]
     pub dtb: Option<PathBuf>,
+    /// Firmware for another core as `core=path`, e.g. `--core-image d0=d0_fw.bin` on the BL808
+    #[structopt(long, number_of_values = 1, conflicts_with = "without-boot2")]
+    pub core_image: Vec<String>,
     /// Without boot2
     #[structopt(short, long)]
     pub without_boot2: bool,
+    /// Flash size the partition table is checked against, detected from the
+    /// device or the chip's usual one when building an image
+    #[structopt(long, parse(try_from_str = parse_int::parse))]
+    pub flash_size: Option<u32>,
+    /// Slot of the FW partition to flash the firmware to, default to slot 0.
+    /// Given a slot, the partition tables are left as they are on the device
+    /// so the slot `partition switch` made active is kept.
+    #[structopt(long)]
+    pub slot: Option<usize>,
+    /// Compress the firmware with xz, boot2 decompresses it into the other FW
+    /// slot. The compressed length goes in the partition table, which --slot
+    /// leaves alone.
+    #[structopt(long, conflicts_with_all = &["without-boot2", "slot"])]
+    pub compress: bool,
+    /// Replace the boot header of images that already have one, instead of
+    /// flashing them as is
+    #[structopt(long)]
+    pub reheader: bool,
+    #[structopt(flatten)]
+    pub security: SecurityOpt,
+}
+
+#[derive(StructOpt)]
+pub struct BootHeaderOpt {
+    /// Path to efuse_bootheader_cfg.conf
+    #[structopt(long, parse(from_os_str))]
+    pub boot_header_cfg: Option<PathBuf>,
+    /// Board profile, a TOML file of boot header fields to override
+    #[structopt(long, parse(from_os_str))]
+    pub board: Option<PathBuf>,
+    /// Override a boot header field after the board profile, e.g. `--set flash_clk_div=1`
+    #[structopt(long = "set", number_of_values = 1)]
+    pub overrides: Vec<String>,
+}
+
+#[derive(StructOpt)]
+pub struct SecurityOpt {
+    /// Sign images for secure boot with this ECDSA P-256 private key (PEM)
+    #[structopt(long, parse(from_os_str))]
+    pub sign_key: Option<PathBuf>,
+    /// Encrypt images with AES-CTR using this 128, 192 or 256-bit key (hex)
+    #[structopt(long)]
+    pub encrypt_key: Option<String>,
+    /// 128-bit AES IV (hex) of the first image, the last 4 bytes must be 0.
+    /// Each further image counts up from it. Default to a random one, or to
+    /// the device's for `check`. IVs are recorded in ~/.blflash/used_ivs once
+    /// flashed, and refused after that.
+    #[structopt(long, requires = "encrypt-key")]
+    pub iv: Option<String>,
+    /// Write the [EFUSE_CFG] matching the keys to this file
+    #[structopt(long, parse(from_os_str))]
+    pub efuse_cfg: Option<PathBuf>,
 }
 
 #[derive(StructOpt)]
 pub struct FlashOpt {
     #[structopt(flatten)]
     pub conn: Connection,
-    /// Bin file
+    /// Bin, ELF, Intel HEX, S-record or UF2 file
     #[structopt(parse(from_os_str))]
     pub image: PathBuf,
     /// Don't skip if hash matches
     #[structopt(short, long)]
     pub force: bool,
+    /// Only rewrite the 4 KiB sectors whose hash differs
+    #[structopt(long, conflicts_with = "force")]
+    pub diff: bool,
     #[structopt(flatten)]
     pub boot: Boot2Opt,
 }
@@ -71,7 +152,7 @@ pub struct FlashOpt {
 pub struct CheckOpt {
     #[structopt(flatten)]
     pub conn: Connection,
-    /// Bin file
+    /// Bin, ELF, Intel HEX, S-record or UF2 file
     #[structopt(parse(from_os_str))]
     pub image: PathBuf,
     #[structopt(flatten)]
@@ -88,9 +169,339 @@ pub struct DumpOpt {
     /// start address
     #[structopt(parse(try_from_str = parse_int::parse), default_value = "0")]
     pub start: u32,
-    /// end address
-    #[structopt(parse(try_from_str = parse_int::parse), default_value = "0x100000")]
-    pub end: u32,
+    /// end address, default to the end of the detected flash
+    #[structopt(parse(try_from_str = parse_int::parse))]
+    pub end: Option<u32>,
+}
+
+#[derive(StructOpt)]
+pub struct WriteOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    /// Name of the partition to write to
+    #[structopt(long)]
+    pub partition: String,
+    /// Partition slot to write to
+    #[structopt(long, default_value = "0")]
+    pub slot: usize,
+    /// Path to partition_cfg.toml, default to read the partition table from the device
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    /// File to write
+    #[structopt(parse(from_os_str))]
+    pub file: PathBuf,
+    /// Don't skip if hash matches
+    #[structopt(short, long)]
+    pub force: bool,
+    /// Only rewrite the 4 KiB sectors whose hash differs
+    #[structopt(long, conflicts_with = "force")]
+    pub diff: bool,
+}
+
+#[derive(StructOpt)]
+pub struct WriteRawOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    /// Files to write as `address:file`, e.g. `0x1f8000:params.dtb`
+    #[structopt(required = true)]
+    pub segments: Vec<RawSegment>,
+    /// Don't skip if hash matches
+    #[structopt(short, long)]
+    pub force: bool,
+    /// Only rewrite the 4 KiB sectors whose hash differs
+    #[structopt(long, conflicts_with = "force")]
+    pub diff: bool,
+    /// Flash size to check the segments against, default to detect it from the flash's JEDEC ID
+    #[structopt(long, parse(try_from_str = parse_int::parse))]
+    pub flash_size: Option<u32>,
+}
+
+/// A file to write at a flash address
+#[derive(Debug, Clone)]
+pub struct RawSegment {
+    pub addr: u32,
+    pub path: PathBuf,
+}
+
+impl FromStr for RawSegment {
+    type Err = Error;
+
+    /// Parse `address:file`, the address is decimal or `0x` hex
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRawSegment(s.to_string());
+        let split = s.find(':').ok_or_else(invalid)?;
+        let addr = parse_int::parse::<u32>(s[..split].trim()).map_err(|_| invalid())?;
+        Ok(RawSegment {
+            addr,
+            path: PathBuf::from(&s[split + 1..]),
+        })
+    }
+}
+
+impl fmt::Display for RawSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}:{}", self.addr, self.path.display())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct ReadOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    /// Name of the partition to read
+    #[structopt(long, required_unless = "all-partitions")]
+    pub partition: Option<String>,
+    /// Partition slot to read
+    #[structopt(long, default_value = "0")]
+    pub slot: usize,
+    /// Read every partition into this directory, with a manifest.toml
+    #[structopt(long, parse(from_os_str), conflicts_with = "partition")]
+    pub all_partitions: Option<PathBuf>,
+    /// Path to partition_cfg.toml, default to read the partition table from the device
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    /// Output file
+    #[structopt(parse(from_os_str), required_unless = "all-partitions")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub enum FlashSource {
+    /// Read from the device's flash
+    Device(Connection),
+    /// Read from a flash dump starting at address 0
+    File {
+        /// Dump file
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Chip family the dump was taken from
+        #[structopt(long, default_value = "bl602")]
+        chip: ChipType,
+    },
+}
+
+#[derive(StructOpt)]
+pub struct PartitionShowOpt {
+    /// Path to partition_cfg.toml to take the table addresses from, default to the chip's bundled one
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    /// Export the partition table to a partition_cfg.toml
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub source: FlashSource,
+}
+
+#[derive(StructOpt)]
+pub struct PartitionCheckOpt {
+    /// Chip family: bl602, bl702, bl808 or bl616
+    #[structopt(long, default_value = "bl602")]
+    pub chip: ChipType,
+    /// Path to partition_cfg.toml, default to the chip's bundled one for the flash size
+    #[structopt(parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    /// Flash size the partition table is checked against, default to the chip's usual one
+    #[structopt(long, parse(try_from_str = parse_int::parse))]
+    pub flash_size: Option<u32>,
+}
+
+#[derive(StructOpt)]
+pub struct PartitionStatusOpt {
+    /// Path to partition_cfg.toml to take the table addresses from, default to the chip's bundled one
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub source: FlashSource,
+}
+
+#[derive(StructOpt)]
+pub struct PartitionSwitchOpt {
+    #[structopt(flatten)]
+    pub conn: Connection,
+    /// Name of the partition to switch
+    #[structopt(long, default_value = "FW")]
+    pub partition: String,
+    /// Slot to make active, default to the inactive one
+    #[structopt(long)]
+    pub slot: Option<usize>,
+    /// Path to partition_cfg.toml to take the table addresses from, default to the chip's bundled one
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub enum PartitionOpt {
+    /// Decode the partition table from the device or a flash dump
+    Show(PartitionShowOpt),
+    /// Check a partition_cfg.toml for layout errors
+    Check(PartitionCheckOpt),
+    /// Show which table copy and slots boot2 will boot from
+    Status(PartitionStatusOpt),
+    /// Switch the active slot of a partition by writing the inactive table copy with a bumped age
+    Switch(PartitionSwitchOpt),
+}
+
+#[derive(StructOpt)]
+pub enum DtbSource {
+    /// The ro_params bundled with blflash
+    Bundled {
+        /// Chip family: bl602, bl702, bl808 or bl616
+        #[structopt(long, default_value = "bl602")]
+        chip: ChipType,
+    },
+    /// A ro_params.dtb or .dts file
+    File {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Read from the device's ro_params partition
+    Device {
+        #[structopt(flatten)]
+        conn: Connection,
+        /// Path to partition_cfg.toml, default to read the partition table from the device
+        #[structopt(long, parse(from_os_str))]
+        partition_cfg: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt)]
+pub struct DtbShowOpt {
+    /// Write the DTS to a file instead of stdout
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub source: DtbSource,
+}
+
+#[derive(StructOpt)]
+pub struct DtbSetOpt {
+    /// Property to set as path=value with the value in DTS syntax, e.g.
+    /// `uart/uart@4000A000/baudrate=<115200>`, `wifi/brd_rf/xtal_mode="MF"`
+    /// or `wifi/mac/sta_mac_addr=[c8 43 57 82 73 40]`
+    #[structopt(long = "set", required = true, number_of_values = 1)]
+    pub settings: Vec<String>,
+    /// Output ro_params.dtb
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+    #[structopt(subcommand)]
+    pub source: DtbSource,
+}
+
+#[derive(StructOpt)]
+pub enum DtbOpt {
+    /// Decode ro_params to DTS
+    Show(DtbShowOpt),
+    /// Change properties of ro_params and write the result
+    Set(DtbSetOpt),
+}
+
+#[derive(StructOpt)]
+pub struct KeysGenerateOpt {
+    /// Directory to write the keys to
+    #[structopt(parse(from_os_str))]
+    pub dir: PathBuf,
+    /// Size of the AES key in bits
+    #[structopt(long, default_value = "128", possible_values = &["128", "192", "256"])]
+    pub aes_bits: usize,
+}
+
+#[derive(StructOpt)]
+pub struct KeysPubhashOpt {
+    /// Public or private key (PEM)
+    #[structopt(parse(from_os_str))]
+    pub key: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct KeysEfuseCfgOpt {
+    /// Public or private signing key (PEM)
+    #[structopt(long, parse(from_os_str))]
+    pub sign_key: Option<PathBuf>,
+    /// Flash encryption AES key (hex)
+    #[structopt(long)]
+    pub encrypt_key: Option<String>,
+    /// Output file, default to stdout
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub enum KeysOpt {
+    /// Generate an ECDSA P-256 signing key, an AES key and IV
+    Generate(KeysGenerateOpt),
+    /// Print the public key hash written to eFuse for secure boot
+    Pubhash(KeysPubhashOpt),
+    /// Render the [EFUSE_CFG] section matching the keys
+    EfuseCfg(KeysEfuseCfgOpt),
+}
+
+#[derive(StructOpt)]
+pub struct ImageOtaOpt {
+    /// Chip family: bl602, bl702, bl808 or bl616
+    #[structopt(long, default_value = "bl602")]
+    pub chip: ChipType,
+    /// Bin, ELF, Intel HEX, S-record or UF2 file
+    #[structopt(parse(from_os_str))]
+    pub image: PathBuf,
+    /// Output file
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+    /// Compress the firmware with xz
+    #[structopt(long)]
+    pub compress: bool,
+    /// Hardware version written to the OTA header
+    #[structopt(long, default_value = "v1.0.0")]
+    pub ver_hardware: String,
+    /// Software version written to the OTA header
+    #[structopt(long, default_value = "v1.0.0")]
+    pub ver_software: String,
+    #[structopt(flatten)]
+    pub boot_header: BootHeaderOpt,
+    #[structopt(flatten)]
+    pub security: SecurityOpt,
+    /// Path to partition_cfg.toml to check the FW slots against, default to the chip's bundled one
+    #[structopt(long, parse(from_os_str))]
+    pub partition_cfg: Option<PathBuf>,
+    /// Write the partition_cfg.toml with the FW entry len matching the package
+    #[structopt(long, parse(from_os_str))]
+    pub partition_output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct ImageBuildOpt {
+    /// Chip family: bl602, bl702, bl808 or bl616
+    #[structopt(long, default_value = "bl602")]
+    pub chip: ChipType,
+    /// Bin, ELF, Intel HEX, S-record or UF2 file
+    #[structopt(parse(from_os_str))]
+    pub image: PathBuf,
+    /// Output file, a whole flash image starting at address 0
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+    #[structopt(flatten)]
+    pub boot: Boot2Opt,
+}
+
+#[derive(StructOpt)]
+pub struct ImageHeaderOpt {
+    /// Chip family: bl602, bl702, bl808 or bl616
+    #[structopt(long, default_value = "bl602")]
+    pub chip: ChipType,
+    #[structopt(flatten)]
+    pub boot_header: BootHeaderOpt,
+    /// Write the boot header config to a file instead of stdout
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub enum ImageOpt {
+    /// Build the flash image that `flash` would write
+    Build(ImageBuildOpt),
+    /// Generate an OTA package
+    Ota(ImageOtaOpt),
+    /// Print the effective boot header config after overrides
+    Header(ImageHeaderOpt),
 }
 
 #[derive(StructOpt)]
@@ -101,6 +512,27 @@ pub enum Opt {
     Check(CheckOpt),
     /// Dump the whole flash to a file
     Dump(DumpOpt),
+    /// Write a file to a partition
+    Write(WriteOpt),
+    /// Write files at flash addresses in one session
+    WriteRaw(WriteRawOpt),
+    /// Read partitions back to files
+    Read(ReadOpt),
+    /// Partition table tools
+    Partition(PartitionOpt),
+    /// Image tools
+    Image(ImageOpt),
+    /// ro_params device tree tools
+    Dtb(DtbOpt),
+    /// Secure boot and flash encryption key tools
+    Keys(KeysOpt),
+}
+
+/// Flash contents to read from, either a connected device or a dump
+pub enum FlashContent {
+    Device(Flasher),
+    /// A flash dump and the chip it was taken from
+    File(Vec<u8>, ChipType),
 }
 
 impl Connection {
@@ -115,77 +547,410 @@ impl Connection {
         })?;
         Ok(serial)
     }
-    pub fn create_flasher(&self, chip: impl Chip + 'static) -> Result<Flasher, Error> {
+    pub fn create_flasher(&self) -> Result<Flasher, Error> {
         let serial = self.open_serial()?;
         Flasher::connect(
-            chip,
+            self.chip.clone(),
             serial,
             BaudRate::from_speed(self.initial_baud_rate),
             BaudRate::from_speed(self.baud_rate),
+            |chip| {
+                let eflash_loader = match &self.eflash_loader {
+                    Some(path) => read(path)?,
+                    None => chip
+                        .get_eflash_loader()
+                        .ok_or_else(|| Error::EflashLoaderNotBundled(chip.name().to_string()))?
+                        .to_vec(),
+                };
+                if !has_boot_header(&eflash_loader) {
+                    return Err(Error::InvalidEflashLoader);
+                }
+                Ok(eflash_loader)
+            },
         )
     }
 }
 
+impl FlashSource {
+    pub fn open(self) -> Result<FlashContent, Error> {
+        Ok(match self {
+            FlashSource::Device(conn) => {
+                let flasher = conn.create_flasher()?;
+                log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+                log::trace!("Boot info: {:x?}", flasher.boot_info());
+                FlashContent::Device(flasher)
+            }
+            FlashSource::File { path, chip } => FlashContent::File(read(path)?, chip),
+        })
+    }
+}
+
+impl FlashContent {
+    pub fn chip_type(&self) -> ChipType {
+        match self {
+            FlashContent::Device(flasher) => flasher.chip_type(),
+            FlashContent::File(_, chip) => chip.clone(),
+        }
+    }
+    pub fn read(&mut self, range: Range<u32>) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            FlashContent::Device(flasher) => flasher.read_flash(range)?,
+            FlashContent::File(data, _) => {
+                let end = (range.end as usize).min(data.len());
+                let start = (range.start as usize).min(end);
+                data[start..end].to_vec()
+            }
+        })
+    }
+}
+
+impl DtbSource {
+    pub fn read(self) -> Result<DeviceTree, Error> {
+        match self {
+            DtbSource::Bundled { chip } => {
+                let chip = chip.chip();
+                let ro_params = chip
+                    .get_ro_params()
+                    .ok_or_else(|| Error::RoParamsNotBundled(chip.name().to_string()))?;
+                DeviceTree::from_fdt(ro_params)
+            }
+            DtbSource::File { path } => dtb::read_device_tree(&read(path)?),
+            DtbSource::Device {
+                conn,
+                partition_cfg,
+            } => {
+                let mut flasher = conn.create_flasher()?;
+                log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+                log::trace!("Boot info: {:x?}", flasher.boot_info());
+
+                let cfg = device_partition_table(&mut flasher, partition_cfg)?;
+                let partition = flasher.chip().ro_params_partition().to_string();
+                let entry = cfg
+                    .find_entry(&partition)
+                    .ok_or(Error::PartitionNotFound(partition))?;
+                let range = entry
+                    .slot(0)
+                    .ok_or_else(|| Error::NoSuchSlot(entry.name.clone(), 0))?;
+                log::info!("Reading ro_params from {:#x}", range.start);
+                DeviceTree::from_fdt(&flasher.read_flash(range)?)
+            }
+        }
+    }
+}
+
+pub fn read_partition_cfg(chip: &dyn Chip, path: Option<PathBuf>) -> Result<PartitionCfg, Error> {
+    partition_cfg_for(chip, path, chip.default_flash_size())
+}
+
+/// Read the given partition_cfg.toml, or the chip's bundled one for a flash
+/// of `flash_size` bytes
+pub fn partition_cfg_for(
+    chip: &dyn Chip,
+    path: Option<PathBuf>,
+    flash_size: u32,
+) -> Result<PartitionCfg, Error> {
+    let partition_cfg = match path {
+        Some(path) => read(path)?,
+        None => {
+            let (size, template) = chip
+                .partition_cfg_template(flash_size)
+                .ok_or(Error::NoPartitionTemplate(flash_size))?;
+            if size != flash_size {
+                log::info!(
+                    "Using the {:#x} byte partition table on the {:#x} byte flash",
+                    size,
+                    flash_size
+                );
+            }
+            template.to_vec()
+        }
+    };
+    Ok(toml::from_slice(&partition_cfg)?)
+}
+
+impl BootHeaderOpt {
+    /// Read efuse_bootheader_cfg.conf with the board profile and `--set`
+    /// overrides applied
+    pub fn read(&self, chip: &dyn Chip) -> Result<Box<dyn BootHeader>, Error> {
+        let boot_header_cfg = self
+            .boot_header_cfg
+            .as_ref()
+            .map(read)
+            .unwrap_or_else(|| Ok(chip.default_boot_header_cfg().to_vec()))?;
+
+        let mut overrides = Vec::new();
+        if let Some(path) = &self.board {
+            let board: toml::value::Table = toml::from_slice(&read(path)?)?;
+            for (field, value) in board {
+                let value = value.as_integer().ok_or_else(|| {
+                    Error::InvalidBootHeaderOverride(format!("{} in {}", field, path.display()))
+                })?;
+                overrides.push((field, value));
+            }
+        }
+        for setting in &self.overrides {
+            let invalid = || Error::InvalidBootHeaderOverride(setting.clone());
+            let split = setting.find('=').ok_or_else(invalid)?;
+            let value =
+                parse_int::parse::<i64>(setting[split + 1..].trim()).map_err(|_| invalid())?;
+            overrides.push((setting[..split].trim().to_string(), value));
+        }
+
+        chip.read_boot_header(&boot_header_cfg, &overrides)
+    }
+}
+
+/// Where the IVs used for encryption are recorded
+fn used_ivs_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".blflash").join("used_ivs"))
+}
+
+impl SecurityOpt {
+    /// The ledger the IVs of the encrypted images are checked against
+    pub fn used_ivs(&self) -> Option<Rc<IvLedger>> {
+        self.encrypt_key.as_ref()?;
+        let ledger = used_ivs_path().map(IvLedger::new);
+        if ledger.is_none() {
+            log::warn!("No home directory, can't check the IVs weren't used before");
+        }
+        ledger
+    }
+    /// Set up signing and encryption on the boot header, checking the IVs
+    /// against `ledger`, and write the matching eFuse config if asked to
+    pub fn apply(
+        &self,
+        boot_header_cfg: &mut dyn BootHeader,
+        ledger: Option<Rc<IvLedger>>,
+    ) -> Result<(), Error> {
+        if let Some(path) = &self.sign_key {
+            let pem = String::from_utf8_lossy(&read(path)?).into_owned();
+            boot_header_cfg.set_sign_key(SignKey::from_pem(&pem)?)?;
+            log::info!("Signing images for secure boot");
+        }
+        if let Some(key) = &self.encrypt_key {
+            let key = parse_aes_key(key)?;
+            let mut key = match &self.iv {
+                Some(iv) => {
+                    let iv =
+                        hex::decode(iv.trim()).map_err(|_| Error::InvalidKey("IV is not hex"))?;
+                    EncryptKey::new(key, &iv)?
+                }
+                None => EncryptKey::random(key)?,
+            };
+            if let Some(ledger) = ledger {
+                key = key.with_ledger(ledger);
+            }
+            log::info!("Encrypting images with AES-{}-CTR", key.key().len() * 8);
+            boot_header_cfg.set_encrypt_key(key)?;
+        }
+        if let Some(path) = &self.efuse_cfg {
+            write_secret(path, boot_header_cfg.efuse_cfg().to_toml().as_bytes())?;
+            log::info!("eFuse config written to {}", path.display());
+        }
+        Ok(())
+    }
+}
+
 impl Boot2Opt {
+    /// Lay out boot2, the partition table and the firmware for a flash of
+    /// `flash_size` bytes, unless `--flash-size` says otherwise
     pub fn with_boot2<'a>(
         self,
         chip: &'a dyn Chip,
+        boot_header_cfg: &mut dyn BootHeader,
         image: &[u8],
+        flash_size: u32,
     ) -> Result<Vec<RomSegment<'a>>, Error> {
-        let partition_cfg = self
-            .partition_cfg
-            .map(read)
-            .unwrap_or_else(|| Ok(bl602::DEFAULT_PARTITION_CFG.to_vec()))?;
-        let boot_header_cfg = self
-            .boot_header_cfg
-            .map(read)
-            .unwrap_or_else(|| Ok(bl602::DEFAULT_BOOTHEADER_CFG.to_vec()))?;
-        let partition_cfg = toml::from_slice(&partition_cfg)?;
-        let BootHeaderCfgFile { boot_header_cfg } = toml::from_slice(&boot_header_cfg)?;
-        let ro_params = self
-            .dtb
-            .map(read)
-            .unwrap_or_else(|| Ok(bl602::RO_PARAMS.to_vec()))?;
+        let customized = self.customizes_header();
+        let flash_size = self.flash_size.unwrap_or(flash_size);
+        let partition_cfg = partition_cfg_for(chip, self.partition_cfg, flash_size)?;
+        partition_cfg.validate(flash_size)?;
+        let boot2 = match self.boot2 {
+            Some(path) => unwrap_image(
+                chip,
+                "The boot2 image",
+                read(path)?,
+                self.reheader,
+                customized,
+            )?,
+            None => chip
+                .get_boot2()
+                .ok_or_else(|| Error::Boot2NotBundled(chip.name().to_string()))?
+                .to_vec(),
+        };
+        let ro_params = match self.dtb {
+            Some(path) => Some(dtb::read_ro_params(read(path)?)?),
+            None => chip.get_ro_params().map(|ro_params| ro_params.to_vec()),
+        };
+
+        let mut core_images = Vec::new();
+        for core_image in self.core_image {
+            let split = core_image
+                .find('=')
+                .ok_or_else(|| Error::InvalidCoreImage(core_image.clone()))?;
+            let data = read(&core_image[split + 1..])?;
+            let data = read_image(chip, &data)?.into_owned();
+            let data = unwrap_image(
+                chip,
+                &format!("The {} image", &core_image[..split]),
+                data,
+                self.reheader,
+                customized,
+            )?;
+            core_images.push((core_image[..split].to_string(), data));
+        }
+        let images = FlashImages {
+            boot2,
+            firmware: image.to_vec(),
+            ro_params,
+            core_images,
+        };
 
-        let segments = chip.with_boot2(partition_cfg, boot_header_cfg, ro_params, image)?;
+        let segments = chip.with_boot2(
+            partition_cfg,
+            boot_header_cfg,
+            images,
+            self.slot,
+            self.compress,
+        )?;
 
         Ok(segments)
     }
     pub fn make_segment<'a>(
-        self,
-        _chip: &'a dyn Chip,
+        boot_header_cfg: &mut dyn BootHeader,
         image: Vec<u8>,
     ) -> Result<RomSegment<'a>, Error> {
-        let boot_header_cfg = self
-            .boot_header_cfg
-            .map(read)
-            .unwrap_or_else(|| Ok(bl602::DEFAULT_BOOTHEADER_CFG.to_vec()))?;
-        let BootHeaderCfgFile {
-            mut boot_header_cfg,
-        } = toml::from_slice(&boot_header_cfg)?;
-        let img = boot_header_cfg.make_image(0x2000, image)?;
+        let img = boot_header_cfg.wrap_image(boot_header_cfg.boot2_offset() as usize, image)?;
 
         Ok(RomSegment::from_vec(0x0, img))
     }
+    /// The segments to flash, with the erase sector size of the flash config
+    /// in the boot header they are made with. IVs are checked against
+    /// `ledger`.
     pub fn get_segments<'a>(
         self,
         chip: &'a dyn Chip,
         image: Vec<u8>,
-    ) -> Result<Vec<RomSegment<'a>>, Error> {
-        Ok(if self.without_boot2 {
-            vec![self.make_segment(chip, Vec::from(image))?]
+        flash_size: u32,
+        ledger: Option<Rc<IvLedger>>,
+    ) -> Result<(Vec<RomSegment<'a>>, u32), Error> {
+        let mut boot_header_cfg = self.boot_header.read(chip)?;
+        let sector_size = boot_header_cfg.sector_size();
+        if is_whole_flash(chip, &image)? {
+            let options = self.layout_options();
+            if !options.is_empty() {
+                return Err(Error::WholeFlashImage(options.join(", ")));
+            }
+            log::info!("Detected a whole flash image, flashing it verbatim");
+            return Ok((vec![RomSegment::from_vec(0x0, image)], sector_size));
+        }
+        self.security.apply(&mut *boot_header_cfg, ledger)?;
+        let image = unwrap_image(
+            chip,
+            "The firmware",
+            image,
+            self.reheader,
+            self.customizes_header(),
+        )?;
+        let segments = if self.without_boot2 {
+            vec![Self::make_segment(&mut *boot_header_cfg, image)?]
         } else {
-            self.with_boot2(chip, &image)?
+            self.with_boot2(chip, &mut *boot_header_cfg, &image, flash_size)?
+        };
+        Ok((segments, sector_size))
+    }
+    /// The options given that only apply when blflash lays out the flash,
+    /// which it doesn't for whole flash images
+    fn layout_options(&self) -> Vec<&'static str> {
+        let header = &self.boot_header;
+        let security = &self.security;
+        [
+            ("--partition-cfg", self.partition_cfg.is_some()),
+            ("--boot2", self.boot2.is_some()),
+            ("--dtb", self.dtb.is_some()),
+            ("--core-image", !self.core_image.is_empty()),
+            ("--slot", self.slot.is_some()),
+            ("--compress", self.compress),
+            ("--boot-header-cfg", header.boot_header_cfg.is_some()),
+            ("--board", header.board.is_some()),
+            ("--set", !header.overrides.is_empty()),
+            ("--sign-key", security.sign_key.is_some()),
+            ("--encrypt-key", security.encrypt_key.is_some()),
+        ]
+        .iter()
+        .filter(|(_, given)| *given)
+        .map(|(option, _)| *option)
+        .collect()
+    }
+    /// Whether the boot header is changed from the chip's default, by keys or
+    /// header settings
+    fn customizes_header(&self) -> bool {
+        let header = &self.boot_header;
+        let security = &self.security;
+        header.boot_header_cfg.is_some()
+            || header.board.is_some()
+            || !header.overrides.is_empty()
+            || security.sign_key.is_some()
+            || security.encrypt_key.is_some()
+    }
+    /// The flash size to lay out for, detected from the device unless given
+    /// or not needed
+    fn device_flash_size(&self, flasher: &mut Flasher) -> Result<u32, Error> {
+        Ok(match self.flash_size {
+            Some(flash_size) => flash_size,
+            None if self.without_boot2 => flasher.chip().default_flash_size(),
+            None => flasher.flash_size()?,
         })
     }
 }
 
+/// Whether `image` is a whole flash dump, boot2 with its header at 0 and the
+/// partition table at its address
+fn is_whole_flash(chip: &dyn Chip, image: &[u8]) -> Result<bool, Error> {
+    let table = read_partition_cfg(chip, None)?.pt_table.address0 as usize;
+    Ok(has_boot_header(image)
+        && image
+            .get(table..)
+            .is_some_and(|table| table.starts_with(PARTITION_TABLE_MAGIC)))
+}
+
+/// Take the header off an image that already has one when `reheader` is
+/// set, otherwise it is flashed as is. Keys and header settings can't apply
+/// to a kept header, so `customized` images must be reheadered.
+fn unwrap_image(
+    chip: &dyn Chip,
+    name: &str,
+    image: Vec<u8>,
+    reheader: bool,
+    customized: bool,
+) -> Result<Vec<u8>, Error> {
+    if !has_boot_header(&image) {
+        return Ok(image);
+    }
+    if !reheader {
+        if customized {
+            return Err(Error::ImageHasBootHeader(name.to_string()));
+        }
+        log::info!("{} already has a boot header, flashing it as is", name);
+        return Ok(image);
+    }
+    log::warn!("{} already has a boot header, replacing it", name);
+    let layout = chip.read_boot_header(chip.default_boot_header_cfg(), &[])?;
+    Ok(layout.strip_header(&image)?.to_vec())
+}
+
 pub fn read_image<'a>(chip: &dyn Chip, image: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
     Ok(if image[0..4] == [0x7f, 0x45, 0x4c, 0x46] {
         log::trace!("Detect ELF");
         // ELF
         let firmware_image = FirmwareImage::from_data(image).map_err(|_| Error::InvalidElf)?;
         Cow::Owned(firmware_image.to_flash_bin(chip))
+    } else if let Some(format) = RecordFormat::detect(image) {
+        log::trace!("Detect {}", format);
+        let records = RecordImage::parse(format, image, chip.uf2_family_id())?;
+        Cow::Owned(records.to_flash_bin(chip)?)
     } else {
         // bin
         Cow::Borrowed(image)
@@ -193,16 +958,24 @@ pub fn read_image<'a>(chip: &dyn Chip, image: &'a [u8]) -> Result<Cow<'a, [u8]>,
 }
 
 pub fn flash(opt: FlashOpt) -> Result<(), Error> {
-    let chip = Bl602;
     let image = read(&opt.image)?;
-    let image = read_image(&chip, &image)?;
 
-    let mut flasher = opt.conn.create_flasher(chip)?;
+    let mut flasher = opt.conn.create_flasher()?;
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    let segments = opt.boot.get_segments(&chip, Vec::from(image))?;
-    flasher.load_segments(opt.force, segments.into_iter())?;
+    let chip = flasher.chip_type().chip();
+    let image = read_image(&*chip, &image)?;
+
+    let flash_size = opt.boot.device_flash_size(&mut flasher)?;
+    let ledger = opt.boot.security.used_ivs();
+    let (segments, sector_size) =
+        opt.boot
+            .get_segments(&*chip, Vec::from(image), flash_size, ledger.clone())?;
+    flasher.load_segments(opt.force, opt.diff, sector_size, segments.into_iter())?;
+    if let Some(ledger) = ledger {
+        ledger.record()?;
+    }
     flasher.reset()?;
 
     log::info!("Success");
@@ -211,30 +984,770 @@ pub fn flash(opt: FlashOpt) -> Result<(), Error> {
 }
 
 pub fn check(opt: CheckOpt) -> Result<(), Error> {
-    let chip = Bl602;
     let image = read(&opt.image)?;
-    let image = read_image(&chip, &image)?;
 
-    let mut flasher = opt.conn.create_flasher(Bl602)?;
+    let mut flasher = opt.conn.create_flasher()?;
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    let segments = opt.boot.get_segments(&chip, Vec::from(image))?;
+    let chip = flasher.chip_type().chip();
+    let image = read_image(&*chip, &image)?;
+
+    let mut boot = opt.boot;
+    let flash_size = boot.device_flash_size(&mut flasher)?;
+    // The images on the device were encrypted with its IV, rebuild them the same
+    if boot.security.encrypt_key.is_some() && boot.security.iv.is_none() {
+        let iv = device_iv(&mut flasher, &boot)?;
+        log::info!("Using the IV on the device, {}", iv);
+        boot.security.iv = Some(iv);
+    }
+    let (segments, _) = boot.get_segments(&*chip, Vec::from(image), flash_size, None)?;
     flasher.check_segments(segments.into_iter())?;
 
     Ok(())
 }
 
+/// The IV of the encrypted image at the start of the flash
+fn device_iv(flasher: &mut Flasher, boot: &Boot2Opt) -> Result<String, Error> {
+    let boot_header_cfg = boot.boot_header.read(flasher.chip())?;
+    let at = boot_header_cfg.iv_offset(boot.security.sign_key.is_some())? as u32;
+    let block = flasher.read_flash(at..at + 20)?;
+    let crc = (&block[16..]).read_u32::<LittleEndian>()?;
+    if crc != crc::crc32::checksum_ieee(&block[..16]) {
+        return Err(Error::NoDeviceIv);
+    }
+    Ok(hex::encode(&block[..16]))
+}
+
+/// Erase sector size of the flash config in the chip's default boot header,
+/// for writes that don't come with a header
+fn default_sector_size(chip: &dyn Chip) -> Result<u32, Error> {
+    Ok(chip
+        .read_boot_header(chip.default_boot_header_cfg(), &[])?
+        .sector_size())
+}
+
+/// Use the given partition_cfg.toml, or read the partition table from the device
+fn device_partition_table(
+    flasher: &mut Flasher,
+    partition_cfg: Option<PathBuf>,
+) -> Result<PartitionCfg, Error> {
+    Ok(match partition_cfg {
+        Some(path) => read_partition_cfg(flasher.chip(), Some(path))?,
+        None => {
+            let default_cfg = read_partition_cfg(flasher.chip(), None)?;
+            read_partition_table(
+                |range| flasher.read_flash(range),
+                default_cfg.pt_table.address0,
+                default_cfg.pt_table.address1,
+            )?
+        }
+    })
+}
+
+pub fn write_partition(opt: WriteOpt) -> Result<(), Error> {
+    let data = read(&opt.file)?;
+    let mut flasher = opt.conn.create_flasher()?;
+    log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+    log::trace!("Boot info: {:x?}", flasher.boot_info());
+
+    let cfg = device_partition_table(&mut flasher, opt.partition_cfg)?;
+    let (name, slot) = (opt.partition, opt.slot);
+    let entry = cfg
+        .find_entry(&name)
+        .ok_or_else(|| Error::PartitionNotFound(name.clone()))?;
+    let range = entry
+        .slot(slot)
+        .ok_or_else(|| Error::NoSuchSlot(entry.name.clone(), slot))?;
+    if data.len() > range.len() {
+        return Err(Error::PartitionTooSmall(
+            entry.name.clone(),
+            data.len(),
+            range.len(),
+        ));
+    }
+    log::info!(
+        "Writing {} bytes to partition {:?} slot {} at {:#x}",
+        data.len(),
+        entry.name,
+        slot,
+        range.start
+    );
+
+    let segment = RomSegment::from_vec(range.start, data);
+    let sector_size = default_sector_size(flasher.chip())?;
+    flasher.load_segments(opt.force, opt.diff, sector_size, std::iter::once(segment))?;
+    flasher.reset()?;
+
+    log::info!("Success");
+
+    Ok(())
+}
+
+pub fn write_raw(opt: WriteRawOpt) -> Result<(), Error> {
+    let mut segments = Vec::new();
+    for raw in opt.segments {
+        let data = read(&raw.path)?;
+        segments.push((raw.to_string(), RomSegment::from_vec(raw.addr, data)));
+    }
+    check_raw_segments(&mut segments, opt.flash_size)?;
+
+    let mut flasher = opt.conn.create_flasher()?;
+    log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+    log::trace!("Boot info: {:x?}", flasher.boot_info());
+    if opt.flash_size.is_none() {
+        let flash_size = flasher.flash_size()?;
+        check_raw_segments(&mut segments, Some(flash_size))?;
+    }
+
+    for (name, segment) in &segments {
+        log::info!("Writing {} bytes from {}", segment.size(), name);
+    }
+    let sector_size = default_sector_size(flasher.chip())?;
+    flasher.load_segments(
+        opt.force,
+        opt.diff,
+        sector_size,
+        segments.into_iter().map(|(_, segment)| segment),
+    )?;
+    flasher.reset()?;
+
+    log::info!("Success");
+
+    Ok(())
+}
+
+/// Sort the segments by address and make sure no two of them overlap and,
+/// given the flash size, that they fit in the flash. Segments sharing a
+/// sector are fine, the flasher keeps the rest of a partially written sector.
+fn check_raw_segments(
+    segments: &mut [(String, RomSegment)],
+    flash_size: Option<u32>,
+) -> Result<(), Error> {
+    segments.sort_by_key(|(_, segment)| segment.addr);
+    let mut ends = Vec::with_capacity(segments.len());
+    for (name, segment) in segments.iter() {
+        let end = segment
+            .end()
+            .map_err(|_| Error::AddressOverflow(name.clone()))?;
+        if let Some(flash_size) = flash_size {
+            if end > flash_size {
+                return Err(Error::SegmentPastFlashEnd(name.clone(), flash_size));
+            }
+        }
+        ends.push(end);
+    }
+    for (index, pair) in segments.windows(2).enumerate() {
+        if ends[index] > pair[1].1.addr {
+            return Err(Error::SegmentOverlap(pair[0].0.clone(), pair[1].0.clone()));
+        }
+    }
+    Ok(())
+}
+
+pub fn read_partition(opt: ReadOpt) -> Result<(), Error> {
+    let mut flasher = opt.conn.create_flasher()?;
+    log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+    log::trace!("Boot info: {:x?}", flasher.boot_info());
+
+    let cfg = device_partition_table(&mut flasher, opt.partition_cfg)?;
+    let slot = opt.slot;
+
+    if let Some(dir) = opt.all_partitions {
+        create_dir_all(&dir)?;
+        let mut manifest = String::new();
+        for entry in &cfg.pt_entry {
+            for (slot, range) in entry.slots() {
+                let file_name = partition_file_name(&entry.name, slot);
+                log::info!(
+                    "Reading partition {:?} slot {} at {:#x}",
+                    entry.name,
+                    slot,
+                    range.start
+                );
+                let data = flasher.read_flash(range.clone())?;
+                write(dir.join(&file_name), &data)?;
+
+                writeln!(manifest, "[[partition]]").unwrap();
+                writeln!(
+                    manifest,
+                    "name = {}",
+                    toml::Value::from(entry.name.as_str())
+                )
+                .unwrap();
+                writeln!(manifest, "slot = {}", slot).unwrap();
+                writeln!(manifest, "file = {}", toml::Value::from(file_name.as_str())).unwrap();
+                writeln!(manifest, "address = 0x{:X}", range.start).unwrap();
+                writeln!(manifest, "size = 0x{:X}", range.len()).unwrap();
+                writeln!(
+                    manifest,
+                    "sha256 = \"{}\"",
+                    hex::encode(Sha256::digest(&data))
+                )
+                .unwrap();
+                writeln!(manifest).unwrap();
+            }
+        }
+        write(dir.join("partition_cfg.toml"), cfg.to_toml())?;
+        write(dir.join("manifest.toml"), manifest)?;
+        log::info!("Partitions written to {}", dir.display());
+    } else if let (Some(name), Some(output)) = (opt.partition, opt.output) {
+        let entry = cfg
+            .find_entry(&name)
+            .ok_or_else(|| Error::PartitionNotFound(name.clone()))?;
+        let range = entry
+            .slot(slot)
+            .ok_or_else(|| Error::NoSuchSlot(entry.name.clone(), slot))?;
+        log::info!(
+            "Reading partition {:?} slot {} at {:#x}",
+            entry.name,
+            slot,
+            range.start
+        );
+        let data = flasher.read_flash(range)?;
+        write(&output, &data)?;
+        log::info!("sha256: {}", hex::encode(Sha256::digest(&data)));
+    }
+
+    log::info!("Success");
+
+    Ok(())
+}
+
+/// File name for a partition slot, `name.bin` for the first slot and `name_<slot>.bin` for others
+fn partition_file_name(name: &str, slot: usize) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    match slot {
+        0 => format!("{}.bin", name),
+        slot => format!("{}_{}.bin", name, slot),
+    }
+}
+
 pub fn dump(opt: DumpOpt) -> Result<(), Error> {
     let mut output = File::create(opt.output)?;
-    let mut flasher = opt.conn.create_flasher(Bl602)?;
+    let mut flasher = opt.conn.create_flasher()?;
 
     log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
     log::trace!("Boot info: {:x?}", flasher.boot_info());
 
-    flasher.dump_flash(opt.start..opt.end, &mut output)?;
+    let end = match opt.end {
+        Some(end) => end,
+        None => flasher.flash_size()?,
+    };
+    flasher.dump_flash(opt.start..end, &mut output)?;
 
     log::info!("Success");
 
     Ok(())
 }
+
+/// Read and verify both copies of the partition table, `None` for an invalid copy
+pub fn read_partition_tables(
+    mut read: impl FnMut(Range<u32>) -> Result<Vec<u8>, Error>,
+    address0: u32,
+    address1: u32,
+) -> Result<[Option<PartitionCfg>; 2], Error> {
+    let mut tables = [None, None];
+    for (index, address) in [address0, address1].iter().enumerate() {
+        let end = address.checked_add(PARTITION_TABLE_SIZE).ok_or_else(|| {
+            Error::InvalidPartitionTable(vec![PartitionError::AddressOverflow(format!(
+                "partition table {} ({:#x})",
+                index, address
+            ))])
+        })?;
+        let data = read(*address..end)?;
+        match PartitionCfg::from_flash(&data) {
+            Ok(mut cfg) => {
+                log::info!(
+                    "Partition table {} at {:#x} is valid, age {}",
+                    index,
+                    address,
+                    cfg.age
+                );
+                cfg.pt_table.address0 = address0;
+                cfg.pt_table.address1 = address1;
+                tables[index] = Some(cfg);
+            }
+            Err(e) => log::warn!("Partition table {} at {:#x}: {}", index, address, e),
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Read the partition table copy boot2 will use
+pub fn read_partition_table(
+    read: impl FnMut(Range<u32>) -> Result<Vec<u8>, Error>,
+    address0: u32,
+    address1: u32,
+) -> Result<PartitionCfg, Error> {
+    let mut tables = read_partition_tables(read, address0, address1)?;
+    let active = PartitionCfg::active_table(&tables).ok_or(Error::NoValidPartitionTable)?;
+
+    Ok(tables[active].take().unwrap())
+}
+
+fn print_partition_table(cfg: &PartitionCfg) {
+    println!(
+        "{:<4} {:<8} {:<6} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "Type", "Name", "Device", "Address0", "Size0", "Address1", "Size1", "Len"
+    );
+    for entry in &cfg.pt_entry {
+        println!(
+            "{:<4} {:<8} {:<6} {:>#10x} {:>#10x} {:>#10x} {:>#10x} {:>#10x}",
+            entry.r#type,
+            entry.name,
+            entry.device,
+            entry.address0,
+            entry.size0,
+            entry.address1,
+            entry.size1,
+            entry.len
+        );
+    }
+}
+
+pub fn partition(opt: PartitionOpt) -> Result<(), Error> {
+    match opt {
+        PartitionOpt::Show(opt) => partition_show(opt),
+        PartitionOpt::Check(opt) => partition_check(opt),
+        PartitionOpt::Status(opt) => partition_status(opt),
+        PartitionOpt::Switch(opt) => partition_switch(opt),
+    }
+}
+
+fn partition_status(opt: PartitionStatusOpt) -> Result<(), Error> {
+    let mut content = opt.source.open()?;
+    let default_cfg = read_partition_cfg(&*content.chip_type().chip(), opt.partition_cfg)?;
+
+    let addresses = [default_cfg.pt_table.address0, default_cfg.pt_table.address1];
+    let tables = read_partition_tables(|range| content.read(range), addresses[0], addresses[1])?;
+    for (index, table) in tables.iter().enumerate() {
+        match table {
+            Some(table) => println!(
+                "Table {} at {:#x}: valid, age {}",
+                index, addresses[index], table.age
+            ),
+            None => println!("Table {} at {:#x}: invalid", index, addresses[index]),
+        }
+    }
+
+    let active = PartitionCfg::active_table(&tables).ok_or(Error::NoValidPartitionTable)?;
+    println!("Boot2 will use table {}", active);
+    println!();
+    println!("{:<8} {:>4} {:>10} {:>6}", "Name", "Slot", "Address", "Age");
+    for entry in &tables[active].as_ref().unwrap().pt_entry {
+        let address = entry
+            .slot(entry.active_index as usize)
+            .map(|range| format!("{:#x}", range.start))
+            .unwrap_or_else(|| "unused".to_string());
+        println!(
+            "{:<8} {:>4} {:>10} {:>6}",
+            entry.name, entry.active_index, address, entry.age
+        );
+    }
+
+    Ok(())
+}
+
+fn partition_switch(opt: PartitionSwitchOpt) -> Result<(), Error> {
+    let mut flasher = opt.conn.create_flasher()?;
+    log::info!("Bootrom version: {}", flasher.boot_info().bootrom_version);
+    log::trace!("Boot info: {:x?}", flasher.boot_info());
+    let default_cfg = read_partition_cfg(flasher.chip(), opt.partition_cfg)?;
+
+    let addresses = [default_cfg.pt_table.address0, default_cfg.pt_table.address1];
+    let mut tables = read_partition_tables(
+        |range| flasher.read_flash(range),
+        addresses[0],
+        addresses[1],
+    )?;
+    let active = PartitionCfg::active_table(&tables).ok_or(Error::NoValidPartitionTable)?;
+    let mut cfg = tables[active].take().unwrap();
+
+    let name = opt.partition;
+    let entry = cfg
+        .find_entry_mut(&name)
+        .ok_or_else(|| Error::PartitionNotFound(name.clone()))?;
+    let slot = opt.slot.unwrap_or(1 - entry.active_index.min(1) as usize);
+    let range = entry
+        .slot(slot)
+        .ok_or_else(|| Error::NoSuchSlot(entry.name.clone(), slot))?;
+    entry.active_index = slot as u8;
+    entry.age = entry.age.wrapping_add(1);
+    log::info!(
+        "Partition {:?} will boot from slot {} at {:#x}",
+        entry.name,
+        slot,
+        range.start
+    );
+
+    // Keep the current table as a fallback and write the other copy
+    cfg.age = cfg.age.wrapping_add(1);
+    let target = 1 - active;
+    log::info!(
+        "Writing partition table {} at {:#x}, age {}",
+        target,
+        addresses[target],
+        cfg.age
+    );
+    let segment = RomSegment::from_vec(addresses[target], cfg.to_flash()?);
+    let sector_size = default_sector_size(flasher.chip())?;
+    flasher.load_segments(false, false, sector_size, std::iter::once(segment))?;
+    flasher.reset()?;
+
+    log::info!("Success");
+
+    Ok(())
+}
+
+fn partition_check(opt: PartitionCheckOpt) -> Result<(), Error> {
+    let chip = opt.chip.chip();
+    let flash_size = opt.flash_size.unwrap_or_else(|| chip.default_flash_size());
+    let cfg = partition_cfg_for(&*chip, opt.partition_cfg, flash_size)?;
+    cfg.validate(flash_size)?;
+    print_partition_table(&cfg);
+
+    log::info!("Partition table is valid");
+
+    Ok(())
+}
+
+fn partition_show(opt: PartitionShowOpt) -> Result<(), Error> {
+    let mut content = opt.source.open()?;
+    let default_cfg = read_partition_cfg(&*content.chip_type().chip(), opt.partition_cfg)?;
+
+    let cfg = read_partition_table(
+        |range| content.read(range),
+        default_cfg.pt_table.address0,
+        default_cfg.pt_table.address1,
+    )?;
+    print_partition_table(&cfg);
+
+    match opt.output {
+        Some(output) => {
+            write(&output, cfg.to_toml())?;
+            log::info!("Partition table written to {}", output.display());
+        }
+        None => print!("\n{}", cfg.to_toml()),
+    }
+
+    Ok(())
+}
+
+pub fn image(opt: ImageOpt) -> Result<(), Error> {
+    match opt {
+        ImageOpt::Build(opt) => image_build(opt),
+        ImageOpt::Ota(opt) => image_ota(opt),
+        ImageOpt::Header(opt) => image_header(opt),
+    }
+}
+
+fn image_header(opt: ImageHeaderOpt) -> Result<(), Error> {
+    let boot_header_cfg = opt.boot_header.read(&*opt.chip.chip())?;
+
+    match opt.output {
+        Some(output) => {
+            write(&output, boot_header_cfg.to_toml()?)?;
+            log::info!("Boot header config written to {}", output.display());
+        }
+        None => print!("{}", boot_header_cfg.to_toml()?),
+    }
+
+    Ok(())
+}
+
+pub fn dtb(opt: DtbOpt) -> Result<(), Error> {
+    match opt {
+        DtbOpt::Show(opt) => dtb_show(opt),
+        DtbOpt::Set(opt) => dtb_set(opt),
+    }
+}
+
+fn dtb_show(opt: DtbShowOpt) -> Result<(), Error> {
+    let tree = opt.source.read()?;
+
+    match opt.output {
+        Some(output) => {
+            write(&output, tree.to_dts())?;
+            log::info!("DTS written to {}", output.display());
+        }
+        None => print!("{}", tree.to_dts()),
+    }
+
+    Ok(())
+}
+
+fn dtb_set(opt: DtbSetOpt) -> Result<(), Error> {
+    let mut tree = opt.source.read()?;
+    for setting in &opt.settings {
+        tree.apply_setting(setting)?;
+    }
+
+    write(&opt.output, tree.to_fdt())?;
+    log::info!("ro_params written to {}", opt.output.display());
+
+    Ok(())
+}
+
+pub fn keys(opt: KeysOpt) -> Result<(), Error> {
+    match opt {
+        KeysOpt::Generate(opt) => keys_generate(opt),
+        KeysOpt::Pubhash(opt) => keys_pubhash(opt),
+        KeysOpt::EfuseCfg(opt) => keys_efuse_cfg(opt),
+    }
+}
+
+/// Options to create a file with, secret ones only readable by the owner
+fn create_options(secret: bool) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if secret { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    options
+}
+
+/// Write a new file, never overwriting existing keys
+fn write_new(path: PathBuf, contents: &[u8], secret: bool) -> Result<(), Error> {
+    let mut file = create_options(secret).create_new(true).open(&path)?;
+    file.write_all(contents)?;
+    log::info!("Written {}", path.display());
+    Ok(())
+}
+
+/// Write a file holding key material, e.g. an [EFUSE_CFG] with the AES key
+fn write_secret(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    create_options(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?
+        .write_all(contents)?;
+    Ok(())
+}
+
+fn keys_generate(opt: KeysGenerateOpt) -> Result<(), Error> {
+    create_dir_all(&opt.dir)?;
+
+    let sign_key = SignKey::generate();
+    let mut aes_key = vec![0; opt.aes_bits / 8];
+    OsRng.fill_bytes(&mut aes_key);
+    // The XIP decryptor adds the flash offset to the last word of the IV
+    let mut iv = [0; 16];
+    OsRng.fill_bytes(&mut iv[..12]);
+
+    write_new(
+        opt.dir.join("private_key.pem"),
+        sign_key.to_pem()?.as_bytes(),
+        true,
+    )?;
+    write_new(
+        opt.dir.join("public_key.pem"),
+        sign_key.public_key_pem()?.as_bytes(),
+        false,
+    )?;
+    write_new(
+        opt.dir.join("aes_key.txt"),
+        hex::encode(&aes_key).as_bytes(),
+        true,
+    )?;
+    write_new(
+        opt.dir.join("aes_iv.txt"),
+        hex::encode(iv).as_bytes(),
+        false,
+    )?;
+
+    println!(
+        "Public key hash: {}",
+        hex::encode(public_key_hash(&sign_key.public_key()))
+    );
+
+    Ok(())
+}
+
+fn keys_pubhash(opt: KeysPubhashOpt) -> Result<(), Error> {
+    let pem = String::from_utf8_lossy(&read(opt.key)?).into_owned();
+    let public_key = read_public_key(&pem)?;
+    println!("{}", hex::encode(public_key_hash(&public_key)));
+
+    Ok(())
+}
+
+fn keys_efuse_cfg(opt: KeysEfuseCfgOpt) -> Result<(), Error> {
+    let mut efuse_cfg = EfuseCfg::default();
+    if let Some(path) = opt.sign_key {
+        let pem = String::from_utf8_lossy(&read(path)?).into_owned();
+        efuse_cfg.set_public_key(&read_public_key(&pem)?);
+    }
+    if let Some(key) = opt.encrypt_key {
+        efuse_cfg.set_aes_key(&parse_aes_key(&key)?);
+    }
+
+    match opt.output {
+        Some(output) => {
+            write_secret(&output, efuse_cfg.to_toml().as_bytes())?;
+            log::info!("eFuse config written to {}", output.display());
+        }
+        None => print!("{}", efuse_cfg.to_toml()),
+    }
+
+    Ok(())
+}
+
+fn image_build(opt: ImageBuildOpt) -> Result<(), Error> {
+    let chip = opt.chip.chip();
+    let image = read(&opt.image)?;
+    let image = read_image(&*chip, &image)?;
+
+    let flash_size = chip.default_flash_size();
+    let ledger = opt.boot.security.used_ivs();
+    let (segments, _) = opt
+        .boot
+        .get_segments(&*chip, Vec::from(image), flash_size, ledger)?;
+    let size = segments
+        .iter()
+        .fold(0, |len, segment| len.max(segment.addr + segment.size()));
+    let mut bin = vec![0xFF; size as usize];
+    for segment in segments {
+        let addr = segment.addr as usize;
+        bin[addr..addr + segment.data.len()].copy_from_slice(&segment.data);
+    }
+
+    write(&opt.output, &bin)?;
+    log::info!("Flash image written to {}", opt.output.display());
+
+    Ok(())
+}
+
+fn image_ota(opt: ImageOtaOpt) -> Result<(), Error> {
+    let chip = opt.chip.chip();
+    let image = read(&opt.image)?;
+    let image = read_image(&*chip, &image)?;
+
+    let mut boot_header_cfg = opt.boot_header.read(&*chip)?;
+    opt.security
+        .apply(&mut *boot_header_cfg, opt.security.used_ivs())?;
+    let fw_image =
+        boot_header_cfg.make_image(boot_header_cfg.fw_offset() as usize, Vec::from(image))?;
+    let ota = make_ota(
+        &fw_image,
+        opt.compress,
+        &opt.ver_hardware,
+        &opt.ver_software,
+    )?;
+    let payload_len = ota.len() - image::OTA_HEADER_LEN;
+
+    // The OTA handler writes the payload to whichever FW slot is inactive
+    let mut partition_cfg = read_partition_cfg(&*chip, opt.partition_cfg)?;
+    let fw = partition_cfg
+        .find_type_mut(FW_TYPE)
+        .ok_or_else(|| Error::PartitionNotFound("FW".to_string()))?;
+    for (slot, range) in fw.slots() {
+        if payload_len > range.len() {
+            return Err(Error::PartitionTooSmall(
+                format!("{} slot {}", fw.name, slot),
+                payload_len,
+                range.len(),
+            ));
+        }
+    }
+
+    // Compressed images must set len, plain ones leave it to 0
+    fw.len = if opt.compress { payload_len as u32 } else { 0 };
+    if opt.compress {
+        log::info!(
+            "Compressed firmware {} -> {} bytes, FW entry len = {:#x}",
+            fw_image.len(),
+            payload_len,
+            fw.len
+        );
+    }
+    if let Some(path) = opt.partition_output {
+        write(&path, partition_cfg.to_toml())?;
+        log::info!("Partition config written to {}", path.display());
+    }
+    write(&opt.output, &ota)?;
+    log::info!("OTA package written to {}", opt.output.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(raw: &[(u32, usize)]) -> Vec<(String, RomSegment<'static>)> {
+        raw.iter()
+            .map(|(addr, len)| {
+                (
+                    format!("{:#x}", addr),
+                    RomSegment::from_vec(*addr, vec![0; *len]),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_raw_segments() {
+        let segment: RawSegment = "0x1f8000:params.dtb".parse().unwrap();
+        assert_eq!(segment.addr, 0x1f8000);
+        assert_eq!(segment.path, PathBuf::from("params.dtb"));
+        let segment: RawSegment = "4096:C:/fw.bin".parse().unwrap();
+        assert_eq!(segment.addr, 4096);
+        assert_eq!(segment.path, PathBuf::from("C:/fw.bin"));
+        assert_eq!(segment.to_string(), "0x1000:C:/fw.bin");
+
+        for invalid in &["fw.bin", "0xzz:fw.bin", "0x100000000:fw.bin", ":fw.bin"] {
+            assert!(matches!(
+                invalid.parse::<RawSegment>(),
+                Err(Error::InvalidRawSegment(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn sorts_raw_segments_and_allows_shared_sectors() {
+        let mut raw = segments(&[(0x2000, 0x10), (0x0, 0x800), (0x800, 0x800)]);
+        check_raw_segments(&mut raw, Some(0x3000)).unwrap();
+        let addrs: Vec<_> = raw.iter().map(|(_, segment)| segment.addr).collect();
+        assert_eq!(addrs, [0x0, 0x800, 0x2000]);
+    }
+
+    #[test]
+    fn rejects_overlapping_raw_segments() {
+        let mut raw = segments(&[(0x1000, 0x1000), (0x1fff, 0x10)]);
+        assert!(matches!(
+            check_raw_segments(&mut raw, None),
+            Err(Error::SegmentOverlap(first, second)) if first == "0x1000" && second == "0x1fff"
+        ));
+    }
+
+    #[test]
+    fn rejects_raw_segments_past_the_flash() {
+        let mut raw = segments(&[(0x1ff000, 0x1001)]);
+        check_raw_segments(&mut raw, None).unwrap();
+        assert!(matches!(
+            check_raw_segments(&mut raw, Some(0x200000)),
+            Err(Error::SegmentPastFlashEnd(_, 0x200000))
+        ));
+
+        let mut raw = segments(&[(0xffff_f000, 0x1001)]);
+        assert!(matches!(
+            check_raw_segments(&mut raw, None),
+            Err(Error::AddressOverflow(_))
+        ));
+    }
+}
@@ -1,4 +1,7 @@
-use blflash::{check, dump, flash, Opt};
+use blflash::{
+    check, dtb, dump, flash, image, keys, partition, read_partition, write_partition, write_raw,
+    Opt,
+};
 use env_logger::Env;
 use main_error::MainError;
 
@@ -12,6 +15,13 @@ fn main(args: Opt) -> Result<(), MainError> {
         Opt::Flash(opt) => flash(opt)?,
         Opt::Check(opt) => check(opt)?,
         Opt::Dump(opt) => dump(opt)?,
+        Opt::Write(opt) => write_partition(opt)?,
+        Opt::WriteRaw(opt) => write_raw(opt)?,
+        Opt::Read(opt) => read_partition(opt)?,
+        Opt::Partition(opt) => partition(opt)?,
+        Opt::Image(opt) => image(opt)?,
+        Opt::Dtb(opt) => dtb(opt)?,
+        Opt::Keys(opt) => keys(opt)?,
     };
 
     Ok(())
@@ -0,0 +1,470 @@
+//! Images that tag their data with addresses: Intel HEX, Motorola S-record
+//! and UF2
+
+use crate::chip::{Chip, CodeSegment};
+use crate::elf::flash_bin;
+use crate::Error;
+use std::fmt;
+
+/// First and last magic of every UF2 block, see https://github.com/microsoft/uf2
+const UF2_MAGIC_START: [u32; 2] = [0x0A324655, 0x9E5D5157];
+const UF2_MAGIC_END: u32 = 0x0AB16F30;
+const UF2_BLOCK_LEN: usize = 512;
+const UF2_HEADER_LEN: usize = 32;
+/// The block isn't meant for the main flash
+const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x1;
+/// The file size field holds the family ID
+const UF2_FLAG_FAMILY_ID: u32 = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    IntelHex,
+    SRecord,
+    Uf2,
+}
+
+impl RecordFormat {
+    /// Recognize the format from the start of the file: the UF2 magics, or
+    /// a whole first line that is a valid HEX or S-record record. Anything
+    /// else is a raw binary.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.len() >= UF2_BLOCK_LEN
+            && read_u32(data, 0) == UF2_MAGIC_START[0]
+            && read_u32(data, 4) == UF2_MAGIC_START[1]
+        {
+            return Some(RecordFormat::Uf2);
+        }
+        let start = data
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(data.len());
+        let end = data[start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(data.len(), |end| start + end);
+        let line = std::str::from_utf8(&data[start..end]).ok()?.trim_end();
+        if let Some(digits) = line.strip_prefix(':') {
+            let bytes = decode_record(RecordFormat::IntelHex, 1, digits, 0).ok()?;
+            if bytes.len() >= 5 && bytes.len() == bytes[0] as usize + 5 {
+                return Some(RecordFormat::IntelHex);
+            }
+        } else if line.len() >= 2 && line.starts_with('S') && line.as_bytes()[1].is_ascii_digit() {
+            let bytes = decode_record(RecordFormat::SRecord, 1, &line[2..], 0xff).ok()?;
+            if !bytes.is_empty() && bytes.len() == bytes[0] as usize + 1 {
+                return Some(RecordFormat::SRecord);
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for RecordFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RecordFormat::IntelHex => "Intel HEX",
+            RecordFormat::SRecord => "S-record",
+            RecordFormat::Uf2 => "UF2",
+        })
+    }
+}
+
+/// Data at CPU addresses, contiguous records merged
+pub struct RecordImage {
+    pub format: RecordFormat,
+    /// (address, data), sorted by address
+    pub chunks: Vec<(u32, Vec<u8>)>,
+}
+
+impl RecordImage {
+    /// Parse `data`, keeping only the UF2 blocks of `uf2_family_id` when the
+    /// chip has one
+    pub fn parse(
+        format: RecordFormat,
+        data: &[u8],
+        uf2_family_id: Option<u32>,
+    ) -> Result<Self, Error> {
+        let mut records = match format {
+            RecordFormat::IntelHex => parse_ihex(data)?,
+            RecordFormat::SRecord => parse_srec(data)?,
+            RecordFormat::Uf2 => parse_uf2(data, uf2_family_id)?,
+        };
+        records.sort_by_key(|(addr, _)| *addr);
+
+        let mut chunks: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (addr, data) in records {
+            match chunks.last_mut() {
+                Some((start, chunk)) if *start as u64 + chunk.len() as u64 == addr as u64 => {
+                    chunk.extend(data)
+                }
+                _ => chunks.push((addr, data)),
+            }
+        }
+        Ok(RecordImage { format, chunks })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = CodeSegment<'_>> {
+        self.chunks
+            .iter()
+            .map(|(addr, data)| CodeSegment::from_slice(*addr, data))
+    }
+
+    /// The flash contents from offset 0, gaps filled with 0xFF. Data outside
+    /// the chip's flash window is left out.
+    pub fn to_flash_bin(&self, chip: &dyn Chip) -> Result<Vec<u8>, Error> {
+        let outside = self
+            .segments()
+            .filter(|segment| {
+                let segment = CodeSegment::from_slice(segment.addr, &segment.data);
+                chip.get_flash_segment(segment).is_none()
+            })
+            .map(|segment| segment.data.len())
+            .sum::<usize>();
+        let bin = flash_bin(chip, self.segments());
+        if bin.is_empty() {
+            return Err(Error::NoFlashData(self.format));
+        }
+        if outside > 0 {
+            log::warn!("Leaving out {} bytes outside the flash", outside);
+        }
+        Ok(bin)
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Decode the hex digits of a text record and check that its bytes add up
+/// to `sum`
+fn decode_record(
+    format: RecordFormat,
+    line: usize,
+    digits: &str,
+    sum: u8,
+) -> Result<Vec<u8>, Error> {
+    let bytes = hex::decode(digits)
+        .map_err(|_| Error::RecordParse(format, line, "invalid hex digits".to_string()))?;
+    if bytes.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte)) != sum {
+        return Err(Error::RecordParse(
+            format,
+            line,
+            "checksum mismatch".to_string(),
+        ));
+    }
+    Ok(bytes)
+}
+
+fn text_lines(format: RecordFormat, data: &[u8]) -> Result<std::str::Lines<'_>, Error> {
+    std::str::from_utf8(data)
+        .map(str::lines)
+        .map_err(|_| Error::RecordParse(format, 0, "file is not text".to_string()))
+}
+
+fn parse_ihex(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let format = RecordFormat::IntelHex;
+    let mut records = Vec::new();
+    let mut base = 0u32;
+    for (index, line) in text_lines(format, data)?.enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| Error::RecordParse(format, index + 1, message.to_string());
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("record doesn't start with ':'"))?;
+        let bytes = decode_record(format, index + 1, digits, 0)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error("record length doesn't match its byte count"));
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => records.push((base.wrapping_add(offset), payload.to_vec())),
+            0x01 => break,
+            0x02 if payload.len() == 2 => {
+                base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 4
+            }
+            0x04 if payload.len() == 2 => {
+                base = (u16::from_be_bytes([payload[0], payload[1]]) as u32) << 16
+            }
+            0x02 | 0x04 => return Err(error("extended address record isn't 2 bytes long")),
+            // Start addresses, the boot header has the entry point
+            0x03 | 0x05 => {}
+            _ => return Err(error("unknown record type")),
+        }
+    }
+    Ok(records)
+}
+
+fn parse_srec(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let format = RecordFormat::SRecord;
+    let mut records = Vec::new();
+    for (index, line) in text_lines(format, data)?.enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| Error::RecordParse(format, index + 1, message.to_string());
+        if !line.starts_with('S') || line.len() < 2 || !line.is_char_boundary(2) {
+            return Err(error("record doesn't start with 'S'"));
+        }
+        let bytes = decode_record(format, index + 1, &line[2..], 0xff)?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error("record length doesn't match its byte count"));
+        }
+        let addr_len = match &line[1..2] {
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            // Header, record counts and start addresses
+            "0" | "5" | "6" => continue,
+            "7" | "8" | "9" => break,
+            _ => return Err(error("unknown record type")),
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(error("record is shorter than its address"));
+        }
+        let addr = bytes[1..1 + addr_len]
+            .iter()
+            .fold(0u32, |addr, byte| (addr << 8) | *byte as u32);
+        records.push((addr, bytes[1 + addr_len..bytes.len() - 1].to_vec()));
+    }
+    Ok(records)
+}
+
+fn parse_uf2(data: &[u8], family_id: Option<u32>) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let format = RecordFormat::Uf2;
+    if !data.len().is_multiple_of(UF2_BLOCK_LEN) {
+        return Err(Error::RecordParse(
+            format,
+            data.len() / UF2_BLOCK_LEN,
+            "file is not made of 512 byte blocks".to_string(),
+        ));
+    }
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for (index, block) in data.chunks(UF2_BLOCK_LEN).enumerate() {
+        let error = |message: &str| Error::RecordParse(format, index, message.to_string());
+        if read_u32(block, 0) != UF2_MAGIC_START[0]
+            || read_u32(block, 4) != UF2_MAGIC_START[1]
+            || read_u32(block, UF2_BLOCK_LEN - 4) != UF2_MAGIC_END
+        {
+            return Err(error("bad block magic"));
+        }
+        let flags = read_u32(block, 8);
+        let family_matches = flags & UF2_FLAG_FAMILY_ID == 0
+            || family_id.is_none_or(|family_id| read_u32(block, 28) == family_id);
+        if flags & UF2_FLAG_NOT_MAIN_FLASH != 0 || !family_matches {
+            skipped += 1;
+            continue;
+        }
+        let addr = read_u32(block, 12);
+        let len = read_u32(block, 16) as usize;
+        if len > UF2_BLOCK_LEN - UF2_HEADER_LEN - 4 {
+            return Err(error("payload is larger than the block"));
+        }
+        records.push((addr, block[UF2_HEADER_LEN..UF2_HEADER_LEN + len].to_vec()));
+    }
+    if skipped > 0 {
+        log::info!(
+            "Skipped {} UF2 blocks not meant for this chip's flash",
+            skipped
+        );
+    }
+    if records.is_empty() {
+        return Err(Error::RecordParse(
+            format,
+            0,
+            "no block for this chip's family".to_string(),
+        ));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::ChipType;
+
+    fn ihex(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&offset.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex::encode_upper(bytes))
+    }
+
+    fn srec(kind: char, addr: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(addr.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(addr);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte));
+        bytes.push(!sum);
+        format!("S{}{}\n", kind, hex::encode_upper(bytes))
+    }
+
+    fn uf2_block(flags: u32, addr: u32, data: &[u8], family_id: u32) -> Vec<u8> {
+        let mut block = vec![0; UF2_BLOCK_LEN];
+        let words = [
+            UF2_MAGIC_START[0],
+            UF2_MAGIC_START[1],
+            flags,
+            addr,
+            data.len() as u32,
+            0,
+            1,
+            family_id,
+        ];
+        for (index, word) in words.iter().enumerate() {
+            block[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        block[UF2_HEADER_LEN..UF2_HEADER_LEN + data.len()].copy_from_slice(data);
+        block[UF2_BLOCK_LEN - 4..].copy_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        block
+    }
+
+    fn parse(format: RecordFormat, data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+        RecordImage::parse(format, data, Some(0xde1270b7)).map(|image| image.chunks)
+    }
+
+    #[test]
+    fn detects_formats_from_a_valid_first_record() {
+        let hex = ihex(0x00, 0, &[1, 2, 3]);
+        assert_eq!(
+            RecordFormat::detect(hex.as_bytes()),
+            Some(RecordFormat::IntelHex)
+        );
+        let srec = srec('1', &[0, 0], &[1, 2, 3]);
+        assert_eq!(
+            RecordFormat::detect(srec.as_bytes()),
+            Some(RecordFormat::SRecord)
+        );
+        let uf2 = uf2_block(0, 0x23000000, &[1], 0);
+        assert_eq!(RecordFormat::detect(&uf2), Some(RecordFormat::Uf2));
+    }
+
+    #[test]
+    fn raw_binaries_that_look_like_records_stay_raw() {
+        assert_eq!(RecordFormat::detect(b":00\x00\x01\x02\xff\xff"), None);
+        assert_eq!(RecordFormat::detect(b":0123456789\n"), None);
+        assert_eq!(RecordFormat::detect(b"S1\x13\x00\xff\xfe"), None);
+        assert_eq!(RecordFormat::detect(b"S1 is not a record\n"), None);
+        assert_eq!(RecordFormat::detect(&uf2_block(0, 0, &[1], 0)[..8]), None);
+    }
+
+    #[test]
+    fn ihex_rejects_bad_checksum() {
+        let mut hex = ihex(0x00, 0, &[1, 2, 3]);
+        hex.replace_range(9..11, "FF");
+        assert!(matches!(
+            parse(RecordFormat::IntelHex, hex.as_bytes()),
+            Err(Error::RecordParse(RecordFormat::IntelHex, 1, _))
+        ));
+    }
+
+    #[test]
+    fn ihex_extended_addresses() {
+        let hex = [
+            ihex(0x04, 0, &[0x23, 0x00]),
+            ihex(0x00, 0x0010, &[1, 2]),
+            ihex(0x02, 0, &[0x10, 0x00]),
+            ihex(0x00, 0x0004, &[3]),
+            ihex(0x01, 0, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            parse(RecordFormat::IntelHex, hex.as_bytes()).unwrap(),
+            vec![(0x10004, vec![3]), (0x23000010, vec![1, 2])]
+        );
+    }
+
+    #[test]
+    fn ihex_rejects_short_extended_addresses() {
+        for kind in &[0x02, 0x04] {
+            let hex = ihex(*kind, 0, &[0x23]);
+            match parse(RecordFormat::IntelHex, hex.as_bytes()) {
+                Err(Error::RecordParse(_, 1, message)) => assert!(message.contains("2 bytes")),
+                other => panic!("unexpected {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn flash_bin_needs_data_in_flash() {
+        let chip = ChipType::Bl602.chip();
+        let hex = [
+            ihex(0x04, 0, &[0x23, 0x00]),
+            ihex(0x00, 0x0002, &[1, 2]),
+            ihex(0x04, 0, &[0x42, 0x00]),
+            ihex(0x00, 0, &[3]),
+        ]
+        .concat();
+        let image = RecordImage::parse(RecordFormat::IntelHex, hex.as_bytes(), None).unwrap();
+        assert_eq!(image.to_flash_bin(&*chip).unwrap(), vec![0xff, 0xff, 1, 2]);
+
+        let ram = ihex(0x04, 0, &[0x42, 0x00]) + &ihex(0x00, 0, &[3]);
+        let image = RecordImage::parse(RecordFormat::IntelHex, ram.as_bytes(), None).unwrap();
+        assert!(matches!(
+            image.to_flash_bin(&*chip),
+            Err(Error::NoFlashData(RecordFormat::IntelHex))
+        ));
+    }
+
+    #[test]
+    fn merges_adjacent_records() {
+        let hex = [
+            ihex(0x00, 0x0004, &[3, 4]),
+            ihex(0x00, 0x0000, &[1, 2]),
+            ihex(0x00, 0x0002, &[5, 6]),
+            ihex(0x00, 0x0010, &[7]),
+        ]
+        .concat();
+        assert_eq!(
+            parse(RecordFormat::IntelHex, hex.as_bytes()).unwrap(),
+            vec![(0, vec![1, 2, 5, 6, 3, 4]), (0x10, vec![7])]
+        );
+    }
+
+    #[test]
+    fn srec_addresses_and_checksum() {
+        let records = [
+            srec('0', &[0, 0], b"hdr"),
+            srec('3', &[0x23, 0, 0, 0], &[1, 2]),
+            srec('2', &[0, 0, 2], &[3]),
+            srec('7', &[0x23, 0, 0, 0], &[]),
+        ]
+        .concat();
+        assert_eq!(
+            parse(RecordFormat::SRecord, records.as_bytes()).unwrap(),
+            vec![(2, vec![3]), (0x23000000, vec![1, 2])]
+        );
+
+        let mut bad = srec('1', &[0, 0], &[1]);
+        bad.replace_range(8..10, "00");
+        assert!(matches!(
+            parse(RecordFormat::SRecord, bad.as_bytes()),
+            Err(Error::RecordParse(RecordFormat::SRecord, 1, _))
+        ));
+    }
+
+    #[test]
+    fn uf2_keeps_the_chip_family() {
+        let uf2 = [
+            uf2_block(UF2_FLAG_FAMILY_ID, 0x23000000, &[1; 4], 0xde1270b7),
+            uf2_block(UF2_FLAG_FAMILY_ID, 0x23000000, &[2; 4], 0xe48bff56),
+            uf2_block(UF2_FLAG_FAMILY_ID, 0x23000004, &[3; 4], 0xde1270b7),
+            uf2_block(UF2_FLAG_NOT_MAIN_FLASH, 0, &[4; 4], 0),
+        ]
+        .concat();
+        assert_eq!(
+            parse(RecordFormat::Uf2, &uf2).unwrap(),
+            vec![(0x23000000, vec![1, 1, 1, 1, 3, 3, 3, 3])]
+        );
+
+        let other = uf2_block(UF2_FLAG_FAMILY_ID, 0, &[1], 0xe48bff56);
+        assert!(parse(RecordFormat::Uf2, &other).is_err());
+    }
+}
@@ -1,10 +1,7 @@
 use std::path::PathBuf;
 use std::process::{exit, Command, ExitStatus, Stdio};
 
-use blflash::{
-    chip::{Bl602, Chip},
-    flash, Boot2Opt, Connection, FlashOpt,
-};
+use blflash::{chip::ChipType, flash, Boot2Opt, Connection, FlashOpt};
 use cargo_project::{Artifact, Profile, Project};
 use color_eyre::{Report, Result};
 use env_logger::Env;
@@ -17,6 +14,9 @@ struct BlflashOpt {
     /// Don't skip if hash matches
     #[structopt(short, long)]
     force: bool,
+    /// Only rewrite the 4 KiB sectors whose hash differs
+    #[structopt(long, conflicts_with = "force")]
+    diff: bool,
     #[structopt(flatten)]
     boot: Boot2Opt,
     #[structopt(long)]
@@ -33,7 +33,9 @@ enum Opt {
 }
 
 fn blflash_main(args: BlflashOpt) -> Result<()> {
-    let chip = Bl602;
+    // The chip is only detected once connected, every supported chip builds for
+    // the same target so any of them will do without --chip
+    let chip = args.conn.chip.as_ref().unwrap_or(&ChipType::Bl602).chip();
     let target = chip.target();
 
     let status = build(args.release, &args.example, &args.features, target);
@@ -48,6 +50,7 @@ fn blflash_main(args: BlflashOpt) -> Result<()> {
         conn: args.conn,
         image: path,
         force: args.force,
+        diff: args.diff,
         boot: args.boot,
     };
 